@@ -1,4 +1,4 @@
-use crate::{c_enum, init};
+use crate::{c_enum, init, netfile};
 
 c_enum!(Bound: u8, LOWER = 0, EXACT = 1, UPPER = 2);
 
@@ -53,7 +53,8 @@ c_enum!(
 c_enum!(
     Rank: [u64; 2],
     PEN = [0xFF000000000000, 0xFF00],
-    DBL = [0xFF000000, 0xFF00000000]
+    DBL = [0xFF000000, 0xFF00000000],
+    BACK = [0xFF, 0xFF00000000000000]
 );
 
 c_enum!(File: u64, A = 0x101010101010101, H = Self::A << 7);
@@ -95,6 +96,15 @@ impl ZobristVals {
     pub fn side(side: usize) -> u64 {
         ZVALS.c[side]
     }
+
+    // identifies the hashing scheme, so hashes saved by another build can be rejected
+    pub fn fingerprint() -> u64 {
+        let pcs = ZVALS.pcs.iter().flatten().flatten();
+        let vals = pcs.chain(&ZVALS.cr).chain(&ZVALS.enp).chain(&ZVALS.c);
+
+        let bytes = vals.flat_map(|val| val.to_le_bytes()).collect::<Vec<_>>();
+        netfile::checksum(&bytes)
+    }
 }
 
 static ZVALS: ZobristVals = {
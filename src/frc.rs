@@ -1,6 +1,6 @@
 use crate::{
     consts::{Piece, Rights, Side},
-    position::Position,
+    position::{FenError, Position},
 };
 
 #[derive(Clone, Copy)]
@@ -33,8 +33,10 @@ impl Castling {
         self.rook_files[side][ks]
     }
 
-    pub fn parse(&mut self, pos: &Position, rights_str: &str) -> u8 {
-        let mut kings = [4, 4];
+    pub fn parse(&mut self, pos: &Position, rights_str: &str) -> Result<u8, FenError> {
+        // rights are cleared by king moves from wherever the king actually is
+        let kings = [Side::WHITE, Side::BLACK]
+            .map(|side| (pos.side(side) & pos.piece(Piece::KING)).trailing_zeros() as usize & 7);
 
         self.chess960 = false;
         self.rook_files[0][0] = 0;
@@ -42,17 +44,19 @@ impl Castling {
         self.rook_files[1][0] = 0;
         self.rook_files[1][1] = 7;
 
-        let rights = rights_str.chars().fold(0, |cr, ch| {
-            cr | match ch as u8 {
+        let mut rights = 0;
+        for ch in rights_str.chars() {
+            rights |= match ch as u8 {
                 b'Q' => Rights::WQS,
                 b'K' => Rights::WKS,
                 b'q' => Rights::BQS,
                 b'k' => Rights::BKS,
-                b'A'..=b'H' => self.parse_castle(pos, Side::WHITE, &mut kings, ch),
-                b'a'..=b'h' => self.parse_castle(pos, Side::BLACK, &mut kings, ch),
-                _ => 0,
+                b'A'..=b'H' => self.parse_castle(pos, Side::WHITE, ch),
+                b'a'..=b'h' => self.parse_castle(pos, Side::BLACK, ch),
+                b'-' if rights_str.len() == 1 => 0,
+                _ => return Err(FenError::InvalidCastling(rights_str.to_string())),
             }
-        });
+        }
 
         for sq in self.castle_mask.iter_mut() {
             *sq = 15;
@@ -65,20 +69,13 @@ impl Castling {
         self.castle_mask[kings[0]] = 3;
         self.castle_mask[kings[1] + 56] = 12;
 
-        rights
+        Ok(rights)
     }
 
-    fn parse_castle(
-        &mut self,
-        pos: &Position,
-        side: usize,
-        kings: &mut [usize; 2],
-        ch: char,
-    ) -> u8 {
+    fn parse_castle(&mut self, pos: &Position, side: usize, ch: char) -> u8 {
         self.chess960 = true;
 
         let wkc = (pos.side(side) & pos.piece(Piece::KING)).trailing_zeros() as u8 & 7;
-        kings[side] = wkc as usize;
         let rook = ch as u8 - [b'A', b'a'][side];
         let i = usize::from(rook > wkc);
 
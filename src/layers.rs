@@ -0,0 +1,136 @@
+use crate::network::{Accumulator, HIDDEN, QA, QB};
+
+// layers after the feature transformer: a sparse int8 L1, then float L2 and L3,
+// with one set of layers per output bucket
+pub const MAX_L1: usize = 256;
+pub const MAX_L2: usize = 64;
+
+const INPUTS: usize = 2 * HIDDEN;
+
+pub struct Layers {
+    l1: usize,
+    l2: usize,
+    buckets: Vec<LayerBucket>,
+}
+
+struct LayerBucket {
+    // quantised by QB, stored as [input / 4][output][input % 4]
+    // so the weights for each group of 4 inputs are contiguous
+    l1_weights: Vec<i8>,
+    l1_bias: Vec<f32>,
+    // [input][output]
+    l2_weights: Vec<f32>,
+    l2_bias: Vec<f32>,
+    l3_weights: Vec<f32>,
+    l3_bias: f32,
+}
+
+// file layout for each bucket, all little-endian:
+// l1 weights i8 [INPUTS][l1], l1 bias f32 [l1],
+// l2 weights f32 [l1][l2], l2 bias f32 [l2],
+// l3 weights f32 [l2], l3 bias f32
+impl Layers {
+    pub const fn supported(l1: usize, l2: usize) -> bool {
+        l1 > 0 && l1 <= MAX_L1 && l2 > 0 && l2 <= MAX_L2
+    }
+
+    pub const fn size(buckets: usize, l1: usize, l2: usize) -> usize {
+        buckets * (INPUTS * l1 + 4 * (l1 + l1 * l2 + 2 * l2 + 1))
+    }
+
+    pub fn parse(mut bytes: &[u8], buckets: usize, l1: usize, l2: usize) -> Self {
+        let mut take = |n: usize| {
+            let (taken, rest) = bytes.split_at(n);
+            bytes = rest;
+            taken
+        };
+
+        let floats = |bytes: &[u8]| {
+            bytes
+                .chunks_exact(4)
+                .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                .collect::<Vec<_>>()
+        };
+
+        let buckets = (0..buckets)
+            .map(|_| {
+                let raw = take(INPUTS * l1);
+                let mut l1_weights = vec![0; INPUTS * l1];
+
+                for (i, row) in raw.chunks_exact(l1).enumerate() {
+                    for (j, &w) in row.iter().enumerate() {
+                        l1_weights[(i / 4) * 4 * l1 + 4 * j + i % 4] = w as i8;
+                    }
+                }
+
+                LayerBucket {
+                    l1_weights,
+                    l1_bias: floats(take(4 * l1)),
+                    l2_weights: floats(take(4 * l1 * l2)),
+                    l2_bias: floats(take(4 * l2)),
+                    l3_weights: floats(take(4 * l2)),
+                    l3_bias: floats(take(4))[0],
+                }
+            })
+            .collect();
+
+        Self { l1, l2, buckets }
+    }
+
+    pub fn forward(&self, boys: &Accumulator, opps: &Accumulator, bucket: usize) -> f32 {
+        let layers = &self.buckets[bucket];
+
+        // screlu, requantised to fit in a byte
+        let mut inputs = [0u8; INPUTS];
+        for (input, &x) in inputs.iter_mut().zip(boys.vals.iter().chain(&opps.vals)) {
+            let x = i32::from(x).clamp(0, QA);
+            *input = (x * x / QA) as u8;
+        }
+
+        // only groups of inputs with a nonzero activation contribute
+        let mut l1_sums = [0i32; MAX_L1];
+        let l1_sums = &mut l1_sums[..self.l1];
+
+        for (group, input) in inputs.chunks_exact(4).enumerate() {
+            if input == [0; 4] {
+                continue;
+            }
+
+            let weights = &layers.l1_weights[group * 4 * self.l1..(group + 1) * 4 * self.l1];
+
+            for (sum, w) in l1_sums.iter_mut().zip(weights.chunks_exact(4)) {
+                for (&x, &w) in input.iter().zip(w) {
+                    *sum += i32::from(x) * i32::from(w);
+                }
+            }
+        }
+
+        let mut l1_out = [0f32; MAX_L1];
+        let l1_out = &mut l1_out[..self.l1];
+
+        for ((out, &sum), &bias) in l1_out.iter_mut().zip(l1_sums.iter()).zip(&layers.l1_bias) {
+            *out = screlu(sum as f32 / (QA * QB) as f32 + bias);
+        }
+
+        let mut l2_out = [0f32; MAX_L2];
+        let l2_out = &mut l2_out[..self.l2];
+        l2_out.copy_from_slice(&layers.l2_bias);
+
+        for (&x, weights) in l1_out.iter().zip(layers.l2_weights.chunks_exact(self.l2)) {
+            for (out, &w) in l2_out.iter_mut().zip(weights) {
+                *out += x * w;
+            }
+        }
+
+        let mut out = layers.l3_bias;
+        for (&x, &w) in l2_out.iter().zip(&layers.l3_weights) {
+            out += screlu(x) * w;
+        }
+
+        out
+    }
+}
+
+fn screlu(x: f32) -> f32 {
+    x.clamp(0.0, 1.0).powi(2)
+}
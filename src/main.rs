@@ -1,10 +1,13 @@
 mod attacks;
 mod consts;
 mod frc;
+mod layers;
 mod moves;
+mod netfile;
 mod network;
 mod position;
 mod search;
+mod simd;
 mod tables;
 mod thread;
 mod uci;
@@ -0,0 +1,185 @@
+// network files are a fixed size header followed by the raw network,
+// all values are little-endian
+pub const MAGIC: [u8; 8] = *b"akimbonn";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 128;
+
+#[derive(Clone, Copy)]
+pub struct NetworkHeader {
+    pub version: u32,
+    pub inputs: u32,
+    pub hidden: u32,
+    pub input_buckets: u32,
+    pub bucket_layout: [u8; 64],
+    pub qa: i32,
+    pub qb: i32,
+    pub scale: i32,
+    pub output_buckets: u32,
+    pub payload_len: u64,
+    pub checksum: u64,
+    // sizes of the layers after the feature transformer, zero if there are none
+    pub l1: u32,
+    pub l2: u32,
+}
+
+#[derive(Clone, Copy)]
+pub enum HeaderError {
+    Missing,
+    Version(u32),
+    Inputs(u32),
+    Hidden(u32),
+    InputBuckets(u32),
+    BucketLayout,
+    Quantisation,
+    Scale(i32),
+    OutputBuckets(u32),
+    Layers(u32, u32),
+    PayloadSize(u64),
+    Checksum,
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(
+                f,
+                "missing header, raw networks can be converted with `akimbo pack`"
+            ),
+            Self::Version(x) => write!(f, "unsupported format version {x}, expected {VERSION}"),
+            Self::Inputs(x) => write!(f, "network has {x} inputs per bucket"),
+            Self::Hidden(x) => write!(f, "network has hidden size {x}"),
+            Self::InputBuckets(x) => write!(f, "network has {x} input buckets"),
+            Self::BucketLayout => write!(f, "network uses a different king bucket layout"),
+            Self::Quantisation => write!(f, "network uses different quantisation"),
+            Self::Scale(x) => write!(f, "network uses eval scale {x}"),
+            Self::OutputBuckets(x) => write!(f, "network has {x} output buckets"),
+            Self::Layers(l1, l2) => write!(f, "network has unsupported layer sizes {l1}, {l2}"),
+            Self::PayloadSize(x) => write!(f, "network payload is {x} bytes"),
+            Self::Checksum => write!(f, "checksum mismatch, file is corrupted"),
+        }
+    }
+}
+
+impl NetworkHeader {
+    pub const fn read(bytes: &[u8]) -> Result<Self, HeaderError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(HeaderError::Missing);
+        }
+
+        let mut i = 0;
+        while i < MAGIC.len() {
+            if bytes[i] != MAGIC[i] {
+                return Err(HeaderError::Missing);
+            }
+            i += 1;
+        }
+
+        let mut bucket_layout = [0; 64];
+        let mut i = 0;
+        while i < 64 {
+            bucket_layout[i] = bytes[24 + i];
+            i += 1;
+        }
+
+        Ok(Self {
+            version: read_u32(bytes, 8),
+            inputs: read_u32(bytes, 12),
+            hidden: read_u32(bytes, 16),
+            input_buckets: read_u32(bytes, 20),
+            bucket_layout,
+            qa: read_u32(bytes, 88) as i32,
+            qb: read_u32(bytes, 92) as i32,
+            scale: read_u32(bytes, 96) as i32,
+            output_buckets: read_u32(bytes, 100),
+            payload_len: read_u64(bytes, 104),
+            checksum: read_u64(bytes, 112),
+            l1: read_u32(bytes, 120),
+            l2: read_u32(bytes, 124),
+        })
+    }
+
+    pub fn write(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.inputs.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.hidden.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.input_buckets.to_le_bytes());
+        bytes[24..88].copy_from_slice(&self.bucket_layout);
+        bytes[88..92].copy_from_slice(&self.qa.to_le_bytes());
+        bytes[92..96].copy_from_slice(&self.qb.to_le_bytes());
+        bytes[96..100].copy_from_slice(&self.scale.to_le_bytes());
+        bytes[100..104].copy_from_slice(&self.output_buckets.to_le_bytes());
+        bytes[104..112].copy_from_slice(&self.payload_len.to_le_bytes());
+        bytes[112..120].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes[120..124].copy_from_slice(&self.l1.to_le_bytes());
+        bytes[124..128].copy_from_slice(&self.l2.to_le_bytes());
+
+        bytes
+    }
+
+    // checks everything except the checksum against the expected header
+    pub const fn matches(&self, expected: &Self) -> Result<(), HeaderError> {
+        if self.version != expected.version {
+            return Err(HeaderError::Version(self.version));
+        }
+
+        if self.inputs != expected.inputs {
+            return Err(HeaderError::Inputs(self.inputs));
+        }
+
+        if self.hidden != expected.hidden {
+            return Err(HeaderError::Hidden(self.hidden));
+        }
+
+        if self.input_buckets != expected.input_buckets {
+            return Err(HeaderError::InputBuckets(self.input_buckets));
+        }
+
+        let mut i = 0;
+        while i < 64 {
+            if self.bucket_layout[i] != expected.bucket_layout[i] {
+                return Err(HeaderError::BucketLayout);
+            }
+            i += 1;
+        }
+
+        if self.qa != expected.qa || self.qb != expected.qb {
+            return Err(HeaderError::Quantisation);
+        }
+
+        if self.scale != expected.scale {
+            return Err(HeaderError::Scale(self.scale));
+        }
+
+        if self.output_buckets != expected.output_buckets {
+            return Err(HeaderError::OutputBuckets(self.output_buckets));
+        }
+
+        if self.l1 != expected.l1 || self.l2 != expected.l2 {
+            return Err(HeaderError::Layers(self.l1, self.l2));
+        }
+
+        if self.payload_len != expected.payload_len {
+            return Err(HeaderError::PayloadSize(self.payload_len));
+        }
+
+        Ok(())
+    }
+}
+
+const fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+const fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    read_u32(bytes, at) as u64 | (read_u32(bytes, at + 4) as u64) << 32
+}
+
+// 64-bit FNV-1a
+pub fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
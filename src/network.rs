@@ -1,26 +1,164 @@
-use crate::util::boxed_and_zeroed;
-
-const HIDDEN: usize = 1024;
+use std::sync::{
+    atomic::{AtomicPtr, Ordering::Relaxed},
+    OnceLock,
+};
+
+use crate::{
+    layers::Layers,
+    netfile::{self, HeaderError, NetworkHeader, HEADER_SIZE},
+    simd::kernels,
+    util::boxed_and_zeroed,
+};
+
+pub const HIDDEN: usize = 1024;
 const SCALE: i32 = 400;
-const QA: i32 = 255;
-const QB: i32 = 64;
+pub const QA: i32 = 255;
+pub const QB: i32 = 64;
 const QAB: i32 = QA * QB;
 
+pub const MAX_OUTPUT_BUCKETS: usize = 8;
+
 #[repr(C)]
 pub struct Network {
     feature_weights: [Accumulator; 768 * NUM_BUCKETS],
     feature_bias: Accumulator,
-    output_weights: [Accumulator; 2],
-    output_bias: i16,
+    output_weights: [[Accumulator; 2]; MAX_OUTPUT_BUCKETS],
+    output_bias: [i16; MAX_OUTPUT_BUCKETS],
+    output_buckets: usize,
+    layers: Option<Box<Layers>>,
+    // payload checksum from the file header, none for the embedded network
+    checksum: Option<u64>,
+}
+
+const ACC_SIZE: usize = std::mem::size_of::<Accumulator>();
+const FEATURES_SIZE: usize = (768 * NUM_BUCKETS + 1) * ACC_SIZE;
+
+// size of a network in the file format, where only the used output buckets
+// are stored and the output biases are padded to a multiple of 64 bytes,
+// networks with further layers store those in place of the output weights
+const fn payload_size(buckets: usize, l1: usize, l2: usize) -> usize {
+    if l1 == 0 {
+        FEATURES_SIZE + 2 * buckets * ACC_SIZE + (2 * buckets).next_multiple_of(64)
+    } else {
+        FEATURES_SIZE + Layers::size(buckets, l1, l2)
+    }
+}
+
+const fn buckets_for_size(size: usize, l1: usize, l2: usize) -> Option<usize> {
+    let mut buckets = 1;
+    while buckets <= MAX_OUTPUT_BUCKETS {
+        if payload_size(buckets, l1, l2) == size {
+            return Some(buckets);
+        }
+        buckets += 1;
+    }
+
+    None
+}
+
+const EMBEDDED: &[u8] = include_bytes!(concat!("../resources/net.bin"));
+
+static NNUE: Network = embedded();
+
+// embedded network may be raw or have a header, checked at compile time
+const fn embedded() -> Network {
+    let (payload, buckets) = match NetworkHeader::read(EMBEDDED) {
+        Ok(header) => {
+            let buckets = header.output_buckets as usize;
+            if header
+                .matches(&Network::header(header.checksum, buckets, 0, 0))
+                .is_err()
+            {
+                panic!("resources/net.bin header does not match the network architecture");
+            }
+
+            (EMBEDDED.split_at(HEADER_SIZE).1, buckets)
+        }
+        Err(_) => match buckets_for_size(EMBEDDED.len(), 0, 0) {
+            Some(buckets) => (EMBEDDED, buckets),
+            None => panic!("resources/net.bin is the wrong size for the network architecture"),
+        },
+    };
+
+    if payload.len() != payload_size(buckets, 0, 0) {
+        panic!("resources/net.bin is the wrong size for the network architecture");
+    }
+
+    type Weights = [Accumulator; 768 * NUM_BUCKETS];
+    type Bytes = [u8; FEATURES_SIZE - ACC_SIZE];
+
+    let (features, outputs) = payload.split_at(FEATURES_SIZE - ACC_SIZE);
+    let (bias, outputs) = outputs.split_at(ACC_SIZE);
+
+    let mut net = Network {
+        feature_weights: unsafe {
+            std::mem::transmute::<Bytes, Weights>(*features.first_chunk().unwrap())
+        },
+        feature_bias: read_acc(bias, 0),
+        output_weights: [[Accumulator { vals: [0; HIDDEN] }; 2]; MAX_OUTPUT_BUCKETS],
+        output_bias: [0; MAX_OUTPUT_BUCKETS],
+        output_buckets: buckets,
+        layers: None,
+        checksum: None,
+    };
+
+    let mut i = 0;
+    while i < buckets {
+        net.output_weights[i] = [read_acc(outputs, 2 * i), read_acc(outputs, 2 * i + 1)];
+        net.output_bias[i] = read_bias(outputs, buckets, i);
+        i += 1;
+    }
+
+    net
+}
+
+// embedded network without its header, if it has one
+const fn embedded_payload() -> &'static [u8] {
+    match NetworkHeader::read(EMBEDDED) {
+        Ok(_) => EMBEDDED.split_at(HEADER_SIZE).1,
+        Err(_) => EMBEDDED,
+    }
+}
+
+const fn read_acc(bytes: &[u8], idx: usize) -> Accumulator {
+    let bytes = bytes.split_at(idx * ACC_SIZE).1;
+    unsafe { std::mem::transmute(*bytes.first_chunk::<ACC_SIZE>().unwrap()) }
+}
+
+const fn read_bias(outputs: &[u8], buckets: usize, idx: usize) -> i16 {
+    let at = 2 * buckets * ACC_SIZE + 2 * idx;
+    i16::from_le_bytes([outputs[at], outputs[at + 1]])
 }
 
-static NNUE: Network =
-    unsafe { std::mem::transmute(*include_bytes!(concat!("../resources/net.bin"))) };
+// network used for evaluation, either the embedded one or loaded from a file
+static NETWORK: AtomicPtr<Network> = AtomicPtr::new(&NNUE as *const Network as *mut Network);
 
-const NUM_BUCKETS: usize = 4;
+fn nnue() -> &'static Network {
+    unsafe { &*NETWORK.load(Relaxed) }
+}
+
+pub enum NetworkError {
+    Io(std::io::Error),
+    WrongSize(usize),
+    Header(HeaderError),
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::WrongSize(size) => {
+                write!(f, "{size} bytes does not match the network architecture")
+            }
+            Self::Header(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+pub const NUM_BUCKETS: usize = 4;
 
 #[rustfmt::skip]
-static BUCKETS: [usize; 64] = [
+const BUCKETS: [usize; 64] = [
     0, 0, 1, 1, 5, 5, 4, 4,
     2, 2, 2, 2, 6, 6, 6, 6,
     3, 3, 3, 3, 7, 7, 7, 7,
@@ -32,10 +170,152 @@ static BUCKETS: [usize; 64] = [
 ];
 
 impl Network {
-    pub fn out(boys: &Accumulator, opps: &Accumulator) -> i32 {
-        let weights = &NNUE.output_weights;
-        let sum = flatten(boys, &weights[0]) + flatten(opps, &weights[1]);
-        (sum / QA + i32::from(NNUE.output_bias)) * SCALE / QAB
+    // header describing the architecture akimbo was compiled with
+    const fn header(checksum: u64, output_buckets: usize, l1: usize, l2: usize) -> NetworkHeader {
+        let output_buckets = if output_buckets == 0 || output_buckets > MAX_OUTPUT_BUCKETS {
+            1
+        } else {
+            output_buckets
+        };
+
+        let (l1, l2) = if Layers::supported(l1, l2) {
+            (l1, l2)
+        } else {
+            (0, 0)
+        };
+
+        let mut bucket_layout = [0; 64];
+        let mut i = 0;
+        while i < 64 {
+            bucket_layout[i] = BUCKETS[i] as u8;
+            i += 1;
+        }
+
+        NetworkHeader {
+            version: netfile::VERSION,
+            inputs: 768,
+            hidden: HIDDEN as u32,
+            input_buckets: NUM_BUCKETS as u32,
+            bucket_layout,
+            qa: QA,
+            qb: QB,
+            scale: SCALE,
+            output_buckets: output_buckets as u32,
+            payload_len: payload_size(output_buckets, l1, l2) as u64,
+            checksum,
+            l1: l1 as u32,
+            l2: l2 as u32,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<(), NetworkError> {
+        let bytes = std::fs::read(path).map_err(NetworkError::Io)?;
+
+        let header = NetworkHeader::read(&bytes).map_err(NetworkError::Header)?;
+        let payload = &bytes[HEADER_SIZE..];
+        let buckets = header.output_buckets as usize;
+        let (l1, l2) = (header.l1 as usize, header.l2 as usize);
+
+        header
+            .matches(&Self::header(header.checksum, buckets, l1, l2))
+            .map_err(NetworkError::Header)?;
+
+        if payload.len() != payload_size(buckets, l1, l2) {
+            return Err(NetworkError::WrongSize(payload.len()));
+        }
+
+        if netfile::checksum(payload) != header.checksum {
+            return Err(NetworkError::Header(HeaderError::Checksum));
+        }
+
+        // layout matches the file up to the end of the used output weights
+        let weights = if l1 == 0 {
+            FEATURES_SIZE + 2 * buckets * ACC_SIZE
+        } else {
+            FEATURES_SIZE
+        };
+        let outputs = &payload[FEATURES_SIZE..];
+
+        let mut net: Box<Network> = unsafe {
+            let mut net = boxed_and_zeroed::<Network>();
+            let ptr = (net.as_mut() as *mut Network).cast::<u8>();
+            std::ptr::copy_nonoverlapping(payload.as_ptr(), ptr, weights);
+            net
+        };
+
+        if l1 == 0 {
+            for (i, bias) in net.output_bias[..buckets].iter_mut().enumerate() {
+                *bias = read_bias(outputs, buckets, i);
+            }
+        } else {
+            net.layers = Some(Box::new(Layers::parse(outputs, buckets, l1, l2)));
+        }
+
+        net.output_buckets = buckets;
+        net.checksum = Some(header.checksum);
+
+        Self::replace(Box::into_raw(net));
+        Ok(())
+    }
+
+    // wraps a raw network from the trainer in a header for this architecture,
+    // the number of output buckets is inferred from the size
+    pub fn pack(input: &str, output: &str, l1: usize, l2: usize) -> Result<(), NetworkError> {
+        let payload = std::fs::read(input).map_err(NetworkError::Io)?;
+
+        if l1 != 0 && !Layers::supported(l1, l2) {
+            return Err(NetworkError::Header(HeaderError::Layers(
+                l1 as u32, l2 as u32,
+            )));
+        }
+
+        let Some(buckets) = buckets_for_size(payload.len(), l1, l2) else {
+            return Err(NetworkError::WrongSize(payload.len()));
+        };
+
+        let header = Self::header(netfile::checksum(&payload), buckets, l1, l2).write();
+        std::fs::write(output, [&header[..], &payload].concat()).map_err(NetworkError::Io)
+    }
+
+    // identifies the network in use, so anything derived from another network can be rejected
+    pub fn checksum() -> u64 {
+        static EMBEDDED_CHECKSUM: OnceLock<u64> = OnceLock::new();
+
+        nnue().checksum.unwrap_or_else(|| {
+            *EMBEDDED_CHECKSUM.get_or_init(|| netfile::checksum(embedded_payload()))
+        })
+    }
+
+    pub fn use_embedded() {
+        Self::replace(&NNUE as *const Network as *mut Network);
+    }
+
+    fn replace(new: *mut Network) {
+        // no search can be running, so nothing still refers to the old network
+        let old = NETWORK.swap(new, Relaxed);
+
+        if !std::ptr::eq(old, &NNUE) && !std::ptr::eq(old, new) {
+            drop(unsafe { Box::from_raw(old) });
+        }
+    }
+
+    pub fn out(boys: &Accumulator, opps: &Accumulator, bucket: usize) -> i32 {
+        let net = nnue();
+
+        if let Some(layers) = &net.layers {
+            return (layers.forward(boys, opps, bucket) * SCALE as f32) as i32;
+        }
+
+        let weights = &net.output_weights[bucket];
+        let sum = kernels().flatten(boys, &weights[0]) + kernels().flatten(opps, &weights[1]);
+        (sum / QA + i32::from(net.output_bias[bucket])) * SCALE / QAB
+    }
+
+    // output buckets are chosen by the number of pieces on the board
+    pub fn output_bucket(pieces: usize) -> usize {
+        let buckets = nnue().output_buckets;
+        // matches the trainer, which rounds the pieces per bucket up
+        ((pieces - 2) / 32usize.div_ceil(buckets)).min(buckets - 1)
     }
 
     pub fn get_bucket<const SIDE: usize>(mut ksq: usize) -> usize {
@@ -57,75 +337,62 @@ impl Network {
             768 * Self::get_bucket::<1>(ksq) + [384, 0][side] + 64 * pc
         }
     }
+
+    pub fn feature_index(persp: usize, feat: &Feature, ksq: usize) -> u16 {
+        let side = usize::from(feat.side);
+        let pc = usize::from(feat.pc) - 2;
+        let flip = if ksq % 8 > 3 { 7 } else { 0 };
+        let sq = usize::from(feat.sq) ^ flip;
+
+        let idx = if persp == 0 {
+            Self::get_base_index::<0>(side, pc, ksq) + sq
+        } else {
+            Self::get_base_index::<1>(side, pc, ksq) + (sq ^ 56)
+        };
+
+        idx as u16
+    }
 }
 
 #[derive(Clone, Copy)]
 #[repr(C, align(64))]
 pub struct Accumulator {
-    vals: [i16; HIDDEN],
+    pub vals: [i16; HIDDEN],
 }
 
 impl Accumulator {
     pub fn update_multi(&mut self, adds: &[u16], subs: &[u16]) {
-        const REGS: usize = 8;
-        const PER: usize = REGS * 16;
-
-        let mut regs = [0i16; PER];
-
-        for i in 0..HIDDEN / PER {
-            let offset = PER * i;
-
-            for (j, reg) in regs.iter_mut().enumerate() {
-                *reg = self.vals[offset + j];
-            }
-
-            for &add in adds {
-                let weights = &NNUE.feature_weights[usize::from(add)];
-
-                for (j, reg) in regs.iter_mut().enumerate() {
-                    *reg += weights.vals[offset + j];
-                }
-            }
-
-            for &sub in subs {
-                let weights = &NNUE.feature_weights[usize::from(sub)];
-
-                for (j, reg) in regs.iter_mut().enumerate() {
-                    *reg -= weights.vals[offset + j];
-                }
-            }
+        kernels().update(self, adds, subs, &nnue().feature_weights);
+    }
 
-            for (j, reg) in regs.iter().enumerate() {
-                self.vals[offset + j] = *reg;
-            }
-        }
+    pub fn update_from(&mut self, src: &Self, adds: &[u16], subs: &[u16]) {
+        kernels().update_from(src, self, adds, subs, &nnue().feature_weights);
     }
 }
 
 impl Default for Accumulator {
     fn default() -> Self {
-        NNUE.feature_bias
+        nnue().feature_bias
     }
 }
 
+// cached accumulator for one perspective and king bucket
 pub struct EvalEntry {
     pub bbs: [u64; 8],
-    pub white: Accumulator,
-    pub black: Accumulator,
+    pub acc: Accumulator,
 }
 
 pub struct EvalTable {
-    pub table: Box<[[EvalEntry; 2 * NUM_BUCKETS]; 2 * NUM_BUCKETS]>,
+    pub table: Box<[[EvalEntry; 2 * NUM_BUCKETS]; 2]>,
 }
 
 impl Default for EvalTable {
     fn default() -> Self {
-        let mut table: Box<[[EvalEntry; 2 * NUM_BUCKETS]; 2 * NUM_BUCKETS]> = unsafe { boxed_and_zeroed() };
+        let mut table: Box<[[EvalEntry; 2 * NUM_BUCKETS]; 2]> = unsafe { boxed_and_zeroed() };
 
         for row in table.iter_mut() {
             for entry in row.iter_mut() {
-                entry.white = Accumulator::default();
-                entry.black = Accumulator::default();
+                entry.acc = Accumulator::default();
             }
         }
 
@@ -133,78 +400,81 @@ impl Default for EvalTable {
     }
 }
 
-fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
-    #[cfg(not(target_feature = "avx2"))]
-    {
-        fallback::flatten(acc, weights)
-    }
-    #[cfg(target_feature = "avx2")]
-    unsafe {
-        avx2::flatten(acc, weights)
-    }
+#[derive(Clone, Copy, Default)]
+pub struct Feature {
+    pub side: u8,
+    pub pc: u8,
+    pub sq: u8,
 }
 
-#[cfg(not(target_feature = "avx2"))]
-mod fallback {
-    use super::{Accumulator, QA};
+// features changed by a move, at most two of each
+#[derive(Clone, Copy, Default)]
+pub struct Delta {
+    pub adds: [Feature; 2],
+    pub subs: [Feature; 2],
+    pub num_adds: u8,
+    pub num_subs: u8,
+    pub refresh: [bool; 2],
+}
 
-    #[inline]
-    pub fn screlu(x: i16) -> i32 {
-        i32::from(x.clamp(0, QA as i16)).pow(2)
+impl Delta {
+    pub fn add(&mut self, side: usize, pc: usize, sq: usize) {
+        self.adds[usize::from(self.num_adds)] = Feature::new(side, pc, sq);
+        self.num_adds += 1;
     }
 
-    #[inline]
-    pub fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
-        let mut sum = 0;
+    pub fn sub(&mut self, side: usize, pc: usize, sq: usize) {
+        self.subs[usize::from(self.num_subs)] = Feature::new(side, pc, sq);
+        self.num_subs += 1;
+    }
+}
 
-        for (&x, &w) in acc.vals.iter().zip(&weights.vals) {
-            sum += screlu(x) * i32::from(w);
+impl Feature {
+    pub fn new(side: usize, pc: usize, sq: usize) -> Self {
+        Self {
+            side: side as u8,
+            pc: pc as u8,
+            sq: sq as u8,
         }
-
-        sum
     }
 }
 
-#[cfg(target_feature = "avx2")]
-mod avx2 {
-    use super::{Accumulator, HIDDEN, QA};
-    use std::arch::x86_64::*;
-
-    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
-        use std::arch::x86_64::*;
-
-        const CHUNK: usize = 16;
+pub struct StackEntry {
+    pub accs: [Accumulator; 2],
+    pub computed: [bool; 2],
+    pub delta: Delta,
+}
 
-        let mut sum = _mm256_setzero_si256();
-        let min = _mm256_setzero_si256();
-        let max = _mm256_set1_epi16(QA as i16);
+// accumulators for each ply of the search, brought up to date lazily
+// from the move deltas when a position is evaluated
+pub struct AccumulatorStack {
+    pub entries: Box<[StackEntry; 128]>,
+    pub cache: EvalTable,
+}
 
-        for i in 0..HIDDEN / CHUNK {
-            let mut v = load_i16s(acc, i * CHUNK);
-            v = _mm256_min_epi16(_mm256_max_epi16(v, min), max);
-            let w = load_i16s(weights, i * CHUNK);
-            let product = _mm256_madd_epi16(v, _mm256_mullo_epi16(v, w));
-            sum = _mm256_add_epi32(sum, product);
-        }
+impl Default for AccumulatorStack {
+    fn default() -> Self {
+        let mut stack = Self {
+            entries: unsafe { boxed_and_zeroed() },
+            cache: EvalTable::default(),
+        };
 
-        horizontal_sum_i32(sum)
+        stack.reset();
+        stack
     }
+}
 
-    #[inline]
-    unsafe fn load_i16s(acc: &Accumulator, start_idx: usize) -> __m256i {
-        _mm256_load_si256(acc.vals.as_ptr().add(start_idx).cast())
+impl AccumulatorStack {
+    // root accumulators are refreshed on first use
+    pub fn reset(&mut self) {
+        let root = &mut self.entries[0];
+        root.computed = [false; 2];
+        root.delta.refresh = [true; 2];
     }
 
-    #[inline]
-    unsafe fn horizontal_sum_i32(sum: __m256i) -> i32 {
-        let upper_128 = _mm256_extracti128_si256::<1>(sum);
-        let lower_128 = _mm256_castsi256_si128(sum);
-        let sum_128 = _mm_add_epi32(upper_128, lower_128);
-        let upper_64 = _mm_unpackhi_epi64(sum_128, sum_128);
-        let sum_64 = _mm_add_epi32(upper_64, sum_128);
-        let upper_32 = _mm_shuffle_epi32::<0b00_00_00_01>(sum_64);
-        let sum_32 = _mm_add_epi32(upper_32, sum_64);
-
-        _mm_cvtsi128_si32(sum_32)
+    pub fn push(&mut self, ply: i32, delta: Delta) {
+        let entry = &mut self.entries[ply as usize];
+        entry.delta = delta;
+        entry.computed = [false; 2];
     }
 }
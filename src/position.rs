@@ -4,7 +4,7 @@ use crate::{
     consts::{Flag, Piece, Rank, Rights, Side, ZobristVals, SEE_VALS},
     frc::Castling,
     moves::{Move, MoveList},
-    network::{Accumulator, EvalTable, Network},
+    network::{Accumulator, AccumulatorStack, Delta, EvalTable, Feature, Network},
 };
 
 #[derive(Clone, Copy, Default)]
@@ -12,6 +12,7 @@ pub struct Position {
     bb: [u64; 8],
     c: bool,
     halfm: u8,
+    fullm: u16,
     enp_sq: u8,
     rights: u8,
     hash: u64,
@@ -31,6 +32,10 @@ impl Position {
         usize::from(self.halfm)
     }
 
+    pub fn rights(&self) -> u8 {
+        self.rights
+    }
+
     pub fn stm(&self) -> usize {
         usize::from(self.c)
     }
@@ -75,14 +80,17 @@ impl Position {
         self.bb[side] & (occ ^ pk) > 0
     }
 
-    fn sq_attacked(&self, sq: usize, side: usize, occ: u64) -> bool {
+    fn attackers(&self, sq: usize, side: usize, occ: u64) -> u64 {
         ((Attacks::knight(sq) & self.bb[Piece::KNIGHT])
             | (Attacks::king(sq) & self.bb[Piece::KING])
             | (Attacks::pawn(side, sq) & self.bb[Piece::PAWN])
             | (Attacks::rook(sq, occ) & (self.bb[Piece::ROOK] | self.bb[Piece::QUEEN]))
             | (Attacks::bishop(sq, occ) & (self.bb[Piece::BISHOP] | self.bb[Piece::QUEEN])))
             & self.bb[side ^ 1]
-            > 0
+    }
+
+    fn sq_attacked(&self, sq: usize, side: usize, occ: u64) -> bool {
+        self.attackers(sq, side, occ) > 0
     }
 
     pub fn get_pc(&self, bit: u64) -> usize {
@@ -108,6 +116,7 @@ impl Position {
         // update state
         self.rights &= castling.mask(to) & castling.mask(from);
         self.halfm += 1;
+        self.fullm += u16::from(self.c);
         self.enp_sq = 0;
         self.c = !self.c;
         if moved == Piece::PAWN || mov.is_capture() {
@@ -172,62 +181,148 @@ impl Position {
         self.sq_attacked(self.ksq(stm), stm, self.bb[0] | self.bb[1])
     }
 
+    pub fn checkers(&self) -> u64 {
+        let stm = self.stm();
+        self.attackers(self.ksq(stm), stm, self.bb[0] | self.bb[1])
+    }
+
     pub fn make_null(&mut self) {
         self.c = !self.c;
         self.enp_sq = 0;
     }
 
-    pub fn eval(&self, cache: &mut EvalTable) -> i32 {
-        let wksq = self.ksq(Side::WHITE);
-        let bksq = self.ksq(Side::BLACK);
+    pub fn eval(&self, accs: &mut AccumulatorStack, ply: i32) -> i32 {
+        let ply = ply as usize;
+
+        self.update_acc(accs, ply, Side::WHITE);
+        self.update_acc(accs, ply, Side::BLACK);
+
+        let [white, black] = &accs.entries[ply].accs;
+        self.eval_from_accs(white, black)
+    }
+
+    fn update_acc(&self, accs: &mut AccumulatorStack, ply: usize, persp: usize) {
+        if accs.entries[ply].computed[persp] {
+            return;
+        }
+
+        // find the closest ply that can be updated from
+        let mut base = ply;
+        while base > 0
+            && !accs.entries[base].computed[persp]
+            && !accs.entries[base].delta.refresh[persp]
+        {
+            base -= 1;
+        }
+
+        if !accs.entries[base].computed[persp] {
+            // king changed bucket along the way
+            let AccumulatorStack { entries, cache } = accs;
+            self.refresh(persp, cache, &mut entries[ply].accs[persp]);
+        } else {
+            // king bucket is unchanged since `base`, so current king square can be used
+            let ksq = self.ksq(persp);
+
+            for i in base + 1..=ply {
+                let (prev, next) = accs.entries.split_at_mut(i);
+                let entry = &mut next[0];
+                let delta = &entry.delta;
+
+                let feat = |f: &Feature| Network::feature_index(persp, f, ksq);
+                let adds = delta.adds.map(|f| feat(&f));
+                let subs = delta.subs.map(|f| feat(&f));
+
+                entry.accs[persp].update_from(
+                    &prev[i - 1].accs[persp],
+                    &adds[..usize::from(delta.num_adds)],
+                    &subs[..usize::from(delta.num_subs)],
+                );
+                entry.computed[persp] = true;
+            }
+        }
 
-        let wbucket = Network::get_bucket::<0>(wksq);
-        let bbucket = Network::get_bucket::<1>(bksq);
+        accs.entries[ply].computed[persp] = true;
+    }
 
-        let entry = &mut cache.table[wbucket][bbucket];
+    // brings the cached accumulator for this king bucket up to date and copies it out
+    fn refresh(&self, persp: usize, cache: &mut EvalTable, acc: &mut Accumulator) {
+        let ksq = self.ksq(persp);
+        let bucket = if persp == Side::WHITE {
+            Network::get_bucket::<0>(ksq)
+        } else {
+            Network::get_bucket::<1>(ksq)
+        };
 
-        let mut addf = [[0; 32]; 2];
-        let mut subf = [[0; 32]; 2];
+        let entry = &mut cache.table[persp][bucket];
 
-        let (adds, subs) = self.fill_diff(&entry.bbs, &mut addf, &mut subf);
+        let mut addf = [0; 32];
+        let mut subf = [0; 32];
 
-        entry.white.update_multi(&addf[0][..adds], &subf[0][..subs]);
-        entry.black.update_multi(&addf[1][..adds], &subf[1][..subs]);
+        let (adds, subs) = self.fill_diff(persp, &entry.bbs, &mut addf, &mut subf);
 
+        entry.acc.update_multi(&addf[..adds], &subf[..subs]);
         entry.bbs = self.bb;
 
-        self.eval_from_accs(&entry.white, &entry.black)
+        *acc = entry.acc;
     }
 
     fn eval_from_accs(&self, white: &Accumulator, black: &Accumulator) -> i32 {
-        let eval = if self.stm() == Side::WHITE {
-            Network::out(white, black)
-        } else {
-            Network::out(black, white)
-        };
+        self.scale(self.network_out(white, black))
+    }
+
+    fn network_out(&self, white: &Accumulator, black: &Accumulator) -> i32 {
+        let bucket = self.output_bucket();
 
-        self.scale(eval)
+        if self.stm() == Side::WHITE {
+            Network::out(white, black, bucket)
+        } else {
+            Network::out(black, white, bucket)
+        }
     }
 
     pub fn eval_from_scratch(&self) -> i32 {
-        let mut table = EvalTable::default();
-        self.eval(&mut table)
+        let mut accs = AccumulatorStack::default();
+        self.eval(&mut accs, 0)
+    }
+
+    // network output before material scaling
+    pub fn raw_eval(&self) -> i32 {
+        let mut accs = AccumulatorStack::default();
+        self.eval(&mut accs, 0);
+
+        let [white, black] = &accs.entries[0].accs;
+        self.network_out(white, black)
+    }
+
+    pub fn output_bucket(&self) -> usize {
+        let pieces = (self.bb[Side::WHITE] | self.bb[Side::BLACK]).count_ones();
+        Network::output_bucket(pieces as usize)
+    }
+
+    pub fn king_buckets(&self) -> [usize; 2] {
+        [
+            Network::get_bucket::<0>(self.ksq(Side::WHITE)),
+            Network::get_bucket::<1>(self.ksq(Side::BLACK)),
+        ]
+    }
+
+    pub fn remove_piece(&mut self, sq: usize) {
+        let bit = 1 << sq;
+        let side = usize::from(self.bb[Side::BLACK] & bit > 0);
+        self.toggle(side, self.get_pc(bit), sq);
     }
 
     fn fill_diff(
         &self,
+        persp: usize,
         bbs: &[u64; 8],
-        add_feats: &mut [[u16; 32]; 2],
-        sub_feats: &mut [[u16; 32]; 2],
+        add_feats: &mut [u16; 32],
+        sub_feats: &mut [u16; 32],
     ) -> (usize, usize) {
         let mut adds = 0;
         let mut subs = 0;
 
-        let wksq = self.ksq(0);
-        let bksq = self.ksq(1);
-
-        let wflip = if wksq % 8 > 3 { 7 } else { 0 };
-        let bflip = if bksq % 8 > 3 { 7 } else { 0 } ^ 56;
+        let ksq = self.ksq(persp);
 
         for side in [Side::WHITE, Side::BLACK] {
             let old_boys = bbs[side];
@@ -237,22 +332,17 @@ impl Position {
                 old_bb &= old_boys;
                 let new_bb = self.bb[piece + 2] & new_boys;
 
-                let wbase = Network::get_base_index::<0>(side, piece, wksq) as u16;
-                let bbase = Network::get_base_index::<1>(side, piece, bksq) as u16;
-
                 let mut add_diff = new_bb & !old_bb;
                 bitloop!(|add_diff, sq| {
-                    let sq = u16::from(sq);
-                    add_feats[0][adds] = wbase + (sq ^ wflip);
-                    add_feats[1][adds] = bbase + (sq ^ bflip);
+                    let feat = Feature::new(side, piece + 2, usize::from(sq));
+                    add_feats[adds] = Network::feature_index(persp, &feat, ksq);
                     adds += 1;
                 });
 
                 let mut sub_diff = old_bb & !new_bb;
                 bitloop!(|sub_diff, sq| {
-                    let sq = u16::from(sq);
-                    sub_feats[0][subs] = wbase + (sq ^ wflip);
-                    sub_feats[1][subs] = bbase + (sq ^ bflip);
+                    let feat = Feature::new(side, piece + 2, usize::from(sq));
+                    sub_feats[subs] = Network::feature_index(persp, &feat, ksq);
                     subs += 1;
                 });
             }
@@ -261,6 +351,51 @@ impl Position {
         (adds, subs)
     }
 
+    // features changed by a move, for updating the accumulators
+    pub fn delta(&self, mov: Move, castling: &Castling) -> Delta {
+        let mut delta = Delta::default();
+
+        let side = self.stm();
+        let moved = mov.moved_pc();
+        let from = mov.from();
+        let to = mov.to();
+
+        delta.sub(side, moved, from);
+
+        if mov.flag() < Flag::PROMO {
+            delta.add(side, moved, to);
+        } else {
+            delta.add(side, mov.promo_pc(), to);
+        }
+
+        match mov.flag() {
+            Flag::KS | Flag::QS => {
+                let ks = usize::from(mov.flag() == Flag::KS);
+                let sf = 56 * side;
+                delta.sub(
+                    side,
+                    Piece::ROOK,
+                    sf + castling.rook_file(side, ks) as usize,
+                );
+                delta.add(side, Piece::ROOK, sf + [3, 5][ks]);
+            }
+            Flag::ENP => delta.sub(side ^ 1, Piece::PAWN, to ^ 8),
+            _ if mov.is_capture() => delta.sub(side ^ 1, self.get_pc(1 << to), to),
+            _ => {}
+        }
+
+        // moving across king buckets, or to the other half of the board, needs a refresh
+        if moved == Piece::KING {
+            delta.refresh[side] = if side == Side::WHITE {
+                Network::get_bucket::<0>(from) != Network::get_bucket::<0>(to)
+            } else {
+                Network::get_bucket::<1>(from) != Network::get_bucket::<1>(to)
+            };
+        }
+
+        delta
+    }
+
     pub fn key_after(&self, mut curr: u64, mov: Move) -> u64 {
         let side = self.stm();
         let opp = side ^ 1;
@@ -278,14 +413,17 @@ impl Position {
     }
 
     fn scale(&self, eval: i32) -> i32 {
-        let mut mat = self.bb[Piece::KNIGHT].count_ones() as i32 * SEE_VALS[Piece::KNIGHT]
+        eval * self.material_scale() / 1024
+    }
+
+    // network output is multiplied by this / 1024
+    pub fn material_scale(&self) -> i32 {
+        let mat = self.bb[Piece::KNIGHT].count_ones() as i32 * SEE_VALS[Piece::KNIGHT]
             + self.bb[Piece::BISHOP].count_ones() as i32 * SEE_VALS[Piece::BISHOP]
             + self.bb[Piece::ROOK].count_ones() as i32 * SEE_VALS[Piece::ROOK]
             + self.bb[Piece::QUEEN].count_ones() as i32 * SEE_VALS[Piece::QUEEN];
 
-        mat = 700 + mat / 32;
-
-        eval * mat / 1024
+        700 + mat / 32
     }
 
     pub fn threats(&self) -> u64 {
@@ -495,6 +633,19 @@ impl Position {
         moves
     }
 
+    pub fn legal_moves(&self, castling: &Castling) -> MoveList {
+        let mut legal = MoveList::ZEROED;
+
+        for &mov in self.movegen::<true>(castling).iter() {
+            let mut tmp = *self;
+            if !tmp.make(mov, castling) {
+                legal.add(mov);
+            }
+        }
+
+        legal
+    }
+
     fn path(&self, side: usize, mut path: u64, occ: u64) -> bool {
         bitloop!(|path, idx| if self.sq_attacked(idx as usize, side, occ) {
             return false;
@@ -521,22 +672,30 @@ impl Position {
             && self.path(side, btwn(kbb, kto), occ)
     }
 
-    pub fn from_fen(fen: &str, castling: &mut Castling) -> Self {
+    pub fn from_fen(fen: &str, castling: &mut Castling) -> Result<Self, FenError> {
         let vec = fen.split_whitespace().collect::<Vec<&str>>();
-        let p = vec[0].chars().collect::<Vec<char>>();
+        let field = |i: usize, name| vec.get(i).copied().ok_or(FenError::MissingField(name));
 
         // board
         let mut pos = Self::default();
         let mut row = 7i16;
         let mut col = 0i16;
 
-        for ch in p {
+        for ch in field(0, "board")?.chars() {
             if ch == '/' {
+                if col != 8 || row == 0 {
+                    return Err(FenError::InvalidBoard);
+                }
+
                 row -= 1;
                 col = 0;
             } else if ('1'..='8').contains(&ch) {
-                col += ch.to_string().parse().unwrap_or(0);
+                col += ch as i16 - '0' as i16;
             } else if let Some(idx) = "PNBRQKpnbrqk".chars().position(|el| el == ch) {
+                if col > 7 {
+                    return Err(FenError::InvalidBoard);
+                }
+
                 let side = usize::from(idx > 5);
                 let pc = idx + 2 - 6 * side;
                 let sq = 8 * row + col;
@@ -544,24 +703,234 @@ impl Position {
                 pos.toggle(side, pc, sq as usize);
 
                 col += 1;
+            } else {
+                return Err(FenError::InvalidPiece(ch));
+            }
+
+            if col > 8 {
+                return Err(FenError::InvalidBoard);
             }
         }
 
+        if row != 0 || col != 8 {
+            return Err(FenError::InvalidBoard);
+        }
+
+        for side in [Side::WHITE, Side::BLACK] {
+            let kings = (pos.bb[side] & pos.bb[Piece::KING]).count_ones();
+            if kings != 1 {
+                return Err(FenError::KingCount(side, kings));
+            }
+
+            if pos.bb[side].count_ones() > 16
+                || (pos.bb[side] & pos.bb[Piece::PAWN]).count_ones() > 8
+            {
+                return Err(FenError::TooManyPieces(side));
+            }
+        }
+
+        if pos.bb[Piece::PAWN] & (Rank::BACK[0] | Rank::BACK[1]) > 0 {
+            return Err(FenError::PawnOnBackRank);
+        }
+
         // state
-        pos.c = vec[1] == "b";
+        pos.c = match field(1, "side to move")? {
+            "w" => false,
+            "b" => true,
+            stm => return Err(FenError::InvalidSideToMove(stm.to_string())),
+        };
 
-        pos.enp_sq = if vec[3] == "-" {
+        // side that just moved can't still be in check
+        let opp = pos.stm() ^ 1;
+        if pos.sq_attacked(pos.ksq(opp), opp, pos.bb[0] | pos.bb[1]) {
+            return Err(FenError::OpponentInCheck);
+        }
+
+        let rights_str = field(2, "castling")?;
+        let enp = field(3, "en passant")?;
+        pos.enp_sq = if enp == "-" {
             0
         } else {
-            let chs: Vec<char> = vec[3].chars().collect();
-            8 * chs[1].to_string().parse::<u8>().unwrap() + chs[0] as u8 - 105
+            pos.parse_en_passant(enp)
+                .ok_or_else(|| FenError::InvalidEnPassant(enp.to_string()))?
         };
 
-        pos.halfm = vec.get(4).unwrap_or(&"0").parse::<u8>().unwrap();
+        let halfm = vec.get(4).unwrap_or(&"0");
+        pos.halfm = halfm
+            .parse::<u8>()
+            .map_err(|_| FenError::InvalidHalfmove(halfm.to_string()))?;
+
+        let fullm = vec.get(5).unwrap_or(&"1");
+        pos.fullm = fullm
+            .parse::<u16>()
+            .map_err(|_| FenError::InvalidFullmove(fullm.to_string()))?
+            .max(1);
+
+        // only commit to new castling config once it is validated
+        let mut new_castling = *castling;
+        pos.rights = new_castling.parse(&pos, rights_str)?;
+        pos.validate_rights(&new_castling, rights_str)?;
+        *castling = new_castling;
+
+        Ok(pos)
+    }
+
+    // fen character of the piece on a square
+    pub fn piece_char(&self, sq: usize) -> Option<char> {
+        let bit = 1 << sq;
+        let pc = self.get_pc(bit);
+
+        (pc != Piece::EMPTY).then(|| {
+            let side = usize::from(self.bb[Side::BLACK] & bit > 0);
+            b"PNBRQKpnbrqk"[6 * side + pc - 2] as char
+        })
+    }
 
-        pos.rights = castling.parse(&pos, vec[2]);
+    pub fn fen(&self, castling: &Castling) -> String {
+        let mut fen = String::new();
 
-        pos
+        // board
+        for row in (0..8).rev() {
+            let mut empty = 0;
+
+            for col in 0..8 {
+                let Some(ch) = self.piece_char(8 * row + col) else {
+                    empty += 1;
+                    continue;
+                };
+
+                if empty > 0 {
+                    fen.push_str(&empty.to_string());
+                    empty = 0;
+                }
+
+                fen.push(ch);
+            }
+
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+
+            if row > 0 {
+                fen.push('/');
+            }
+        }
+
+        // state
+        fen.push_str([" w ", " b "][self.stm()]);
+
+        let rights = [Rights::WKS, Rights::WQS, Rights::BKS, Rights::BQS];
+        for (i, right) in rights.into_iter().enumerate() {
+            if self.rights & right == 0 {
+                continue;
+            }
+
+            let (side, ks) = (i / 2, 1 - i % 2);
+            fen.push(if castling.is_chess960() {
+                (castling.rook_file(side, ks) + [b'A', b'a'][side]) as char
+            } else {
+                b"KQkq"[i] as char
+            });
+        }
+
+        if self.rights == 0 {
+            fen.push('-');
+        }
+
+        if self.enp_sq > 0 {
+            let sq = self.enp_sq;
+            fen.push_str(&format!(" {}{} ", (b'a' + sq % 8) as char, sq / 8 + 1));
+        } else {
+            fen.push_str(" - ");
+        }
+
+        fen.push_str(&format!("{} {}", self.halfm, self.fullm));
+
+        fen
+    }
+
+    fn parse_en_passant(&self, enp: &str) -> Option<u8> {
+        let chs = enp.as_bytes();
+        if chs.len() != 2 || !(b'a'..=b'h').contains(&chs[0]) {
+            return None;
+        }
+
+        // pawn of the side that just moved must have double pushed past the square
+        let side = self.stm() ^ 1;
+        let rank = [b'3', b'6'][side];
+        let sq = 8 * (rank - b'1') + chs[0] - b'a';
+        let pawn = usize::from(sq) ^ 8;
+        let occ = self.bb[0] | self.bb[1];
+
+        let valid = chs[1] == rank
+            && occ & ((1 << sq) | (1 << (usize::from(sq) ^ 24))) == 0
+            && self.bb[side] & self.bb[Piece::PAWN] & (1 << pawn) > 0;
+
+        valid.then_some(sq)
+    }
+
+    fn validate_rights(&self, castling: &Castling, rights_str: &str) -> Result<(), FenError> {
+        for (side, rights) in [
+            (Side::WHITE, [Rights::WQS, Rights::WKS]),
+            (Side::BLACK, [Rights::BQS, Rights::BKS]),
+        ] {
+            let back = Rank::BACK[side];
+            for (ks, right) in rights.into_iter().enumerate() {
+                if self.rights & right == 0 {
+                    continue;
+                }
+
+                let rook = 1 << (56 * side + usize::from(castling.rook_file(side, ks)));
+                let king = self.bb[side] & self.bb[Piece::KING];
+
+                // standard rights need the king on the e-file
+                let misplaced = !castling.is_chess960() && king != 1 << (56 * side + 4);
+
+                if king & back == 0 || misplaced || self.bb[side] & self.bb[Piece::ROOK] & rook == 0
+                {
+                    return Err(FenError::InvalidCastling(rights_str.to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum FenError {
+    MissingField(&'static str),
+    InvalidBoard,
+    InvalidPiece(char),
+    KingCount(usize, u32),
+    TooManyPieces(usize),
+    PawnOnBackRank,
+    InvalidSideToMove(String),
+    OpponentInCheck,
+    InvalidCastling(String),
+    InvalidEnPassant(String),
+    InvalidHalfmove(String),
+    InvalidFullmove(String),
+}
+
+impl std::fmt::Display for FenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let side = |side: &usize| ["white", "black"][*side];
+
+        match self {
+            Self::MissingField(name) => write!(f, "missing {name} field"),
+            Self::InvalidBoard => write!(f, "board must have 8 ranks of 8 squares"),
+            Self::InvalidPiece(ch) => write!(f, "invalid piece '{ch}'"),
+            Self::KingCount(s, n) => write!(f, "{} has {n} kings", side(s)),
+            Self::TooManyPieces(s) => write!(f, "{} has too many pieces", side(s)),
+            Self::PawnOnBackRank => write!(f, "pawns on first or last rank"),
+            Self::InvalidSideToMove(stm) => write!(f, "invalid side to move '{stm}'"),
+            Self::OpponentInCheck => write!(f, "side not to move is in check"),
+            Self::InvalidCastling(x) => write!(f, "invalid castling rights '{x}'"),
+            Self::InvalidEnPassant(sq) => write!(f, "invalid en passant square '{sq}'"),
+            Self::InvalidHalfmove(x) => write!(f, "invalid halfmove clock '{x}'"),
+            Self::InvalidFullmove(x) => write!(f, "invalid fullmove number '{x}'"),
+        }
     }
 }
 
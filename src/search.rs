@@ -1,16 +1,10 @@
-use std::{
-    sync::atomic::{AtomicU64, Ordering::Relaxed},
-    time::Instant,
-};
-
-// used for displaying accurate node counts when multithreading
-static DISPLAY_NODES: AtomicU64 = AtomicU64::new(0);
+use std::{sync::atomic::Ordering::Relaxed, time::Instant};
 
 use super::{
-    consts::{Bound, MoveScore, Score},
+    consts::{Bound, MoveScore, Piece, Score},
     moves::{Move, MoveList},
+    network::Delta,
     position::Position,
-    tables::NodeTable,
     thread::ThreadData,
     tunable_params,
 };
@@ -46,8 +40,23 @@ tunable_params! {
     hist_prune_margin = 1682, 512, 2048, 256;
 }
 
+fn captured_pc(mov: Move, pos: &Position) -> usize {
+    if mov.is_en_passant() {
+        Piece::PAWN
+    } else {
+        pos.get_pc(1 << mov.to())
+    }
+}
+
 fn mvv_lva(mov: Move, pos: &Position) -> i32 {
-    8 * pos.get_pc(1 << mov.to()) as i32 - mov.moved_pc() as i32
+    8 * captured_pc(mov, pos) as i32 - mov.moved_pc() as i32
+}
+
+// history only reorders captures of similar value
+fn capture_score(mov: Move, pos: &Position, td: &ThreadData) -> i32 {
+    let captured = captured_pc(mov, pos);
+    let hist = td.caphtable.get_score(pos.stm(), mov, captured);
+    128 * mvv_lva(mov, pos) + hist / 16
 }
 
 pub fn go(
@@ -58,69 +67,84 @@ pub fn go(
     soft_bound: f64,
     soft_nodes: u64,
 ) -> (Move, i32) {
-    DISPLAY_NODES.store(0, Relaxed);
-
-    // reset engine
-    td.store_stop(false);
-    td.ntable = NodeTable::default();
+    // reset engine, stop is left alone as another thread may already have set it
+    td.ntable.clear();
     td.plied.clear();
     td.timing = Instant::now();
+    td.time_offset = 0;
+    td.pondering = td.infinite.load(Relaxed);
     td.nodes = 0;
     td.qnodes = 0;
+    td.flushed_nodes = 0;
     td.ply = 0;
+    td.accs.reset();
     td.best_move = Move::NULL;
     td.seldepth = 0;
+    td.completed_depth = 0;
 
     let mut best_move = Move::NULL;
-    let mut eval = 0;
     let mut score = 0;
     td.plied[0].in_check = start.in_check();
 
-    // iterative deepening loop
-    for d in 1..=max_depth {
-        eval = if d < 7 {
-            pvs(start, td, -Score::MAX, Score::MAX, d, false)
-        } else {
-            aspiration(start, td, eval, d, &mut best_move)
-        };
-
-        if td.stop_is_set() {
-            break;
-        }
-
-        best_move = td.best_move;
-        score = eval;
+    // can't report more lines than there are moves to search
+    let root_moves = if td.root_moves.is_empty() {
+        start.legal_moves(&td.castling).len()
+    } else {
+        td.root_moves.len()
+    };
+    let multi_pv = td.multi_pv.clamp(1, root_moves.max(1));
+    // (score, best move, seldepth, pv) of each line in the current iteration
+    let mut lines: Vec<(i32, Move, i32, MoveList)> = Vec::with_capacity(multi_pv);
 
-        if main_thread {
-            // UCI output
-            print!("info depth {d} seldepth {} ", td.seldepth);
-
-            // format mate scores if appropriate
-            if eval.abs() >= Score::MATE {
-                let mate_in = if eval < 0 {
-                    eval.abs() - Score::MAX
-                } else {
-                    Score::MAX - eval + 1
-                };
-
-                print!("score mate {} ", mate_in / 2);
+    // iterative deepening loop
+    'deepening: for d in 1..=max_depth {
+        td.root_excluded.clear();
+        lines.clear();
+
+        // aspiration re-searches can finish an iteration below its nominal depth
+        let mut searched_depth = d;
+
+        for pv_index in 0..multi_pv {
+            // each line scores no higher than the one before it
+            let centre = lines.last().map_or(score, |line| line.0);
+
+            // only moves from the first line are kept if the search is aborted
+            let mut line_best = best_move;
+            let mut line_depth = d;
+            let eval = if d < 7 {
+                pvs(start, td, -Score::MAX, Score::MAX, d, false)
             } else {
-                print!("score cp {eval} ");
+                aspiration(start, td, centre, &mut line_depth, &mut line_best)
             };
 
-            let time = td.timer();
-            let nodes = DISPLAY_NODES.load(Relaxed);
-            let nps = (1000.0 * nodes as f64 / time as f64) as u32;
+            if pv_index == 0 {
+                best_move = line_best;
+            }
 
-            print!("time {time} nodes {nodes} nps {nps} pv");
+            if td.stop_is_set() {
+                break 'deepening;
+            }
 
-            // output pv line
-            for mov in td.plied[0].pv_line.iter() {
-                print!(" {}", mov.to_uci(&td.castling));
+            searched_depth = searched_depth.min(line_depth);
+            td.root_excluded.add(td.best_move);
+            lines.push((eval, td.best_move, td.seldepth, td.plied[0].pv_line));
+        }
+
+        // lines are reported best first
+        lines.sort_by_key(|line| -line.0);
+        (score, best_move, _, td.root_pv) = lines[0];
+
+        if main_thread {
+            for (idx, (eval, _, seldepth, pv)) in lines.iter().enumerate() {
+                let line = (multi_pv > 1).then_some(idx + 1);
+                print_info(td, d, *seldepth, *eval, pv, line);
             }
+        }
 
-            println!();
+        td.completed_depth = searched_depth;
 
+        if main_thread && !td.check_pondering() {
+            let time = td.time_used();
             let frac = td.ntable.get(best_move) as f64 / td.nodes() as f64;
             let multiplier = if d > 8 { (1.5 - frac) * 1.35 } else { 1.0 };
 
@@ -131,29 +155,96 @@ pub fn go(
             }
         }
 
-        // soft node limit
-        if td.nodes() > soft_nodes {
+        // soft node limit, counted across all threads
+        if td.total_nodes() > soft_nodes {
+            td.store_stop(true);
+            break;
+        }
+
+        // mate scores are proven when searching without pruning
+        if td.mate_search && score.abs() >= Score::MATE {
+            td.store_stop(true);
             break;
         }
     }
 
+    // stopped before the first iteration finished
+    if best_move == Move::NULL {
+        let legal = start.legal_moves(&td.castling);
+        let allowed = |mov: &&Move| td.root_moves.is_empty() || td.root_moves.contains(mov);
+
+        if let Some(&mov) = legal.iter().find(allowed) {
+            best_move = mov;
+        }
+    }
+
+    td.flush_nodes();
+
     (best_move, score)
 }
 
+pub fn print_info(
+    td: &ThreadData,
+    depth: i32,
+    seldepth: i32,
+    eval: i32,
+    pv: &[Move],
+    multi_pv: Option<usize>,
+) {
+    print!("info depth {depth} seldepth {seldepth} ");
+
+    if let Some(k) = multi_pv {
+        print!("multipv {k} ");
+    }
+
+    // format mate scores if appropriate
+    if eval.abs() >= Score::MATE {
+        let mate_in = if eval < 0 {
+            eval.abs() - Score::MAX
+        } else {
+            Score::MAX - eval + 1
+        };
+
+        print!("score mate {} ", mate_in / 2);
+    } else {
+        print!("score cp {eval} ");
+    };
+
+    let time = td.timer();
+    let nodes = td.total_nodes();
+    let nps = (1000.0 * nodes as f64 / time as f64) as u32;
+
+    let hashfull = td.tt.hashfull();
+
+    // timings differ between runs
+    if td.deterministic {
+        print!("nodes {nodes} hashfull {hashfull} pv");
+    } else {
+        print!("time {time} nodes {nodes} nps {nps} hashfull {hashfull} pv");
+    }
+
+    // output pv line
+    for mov in pv {
+        print!(" {}", mov.to_uci(&td.castling));
+    }
+
+    println!();
+}
+
 fn aspiration(
     pos: &Position,
     td: &mut ThreadData,
     mut score: i32,
-    max_depth: i32,
+    depth: &mut i32,
     best_move: &mut Move,
 ) -> i32 {
     let mut delta = 16;
     let mut alpha = (-Score::MAX).max(score - delta);
     let mut beta = Score::MAX.min(score + delta);
-    let mut depth = max_depth;
+    let max_depth = *depth;
 
     loop {
-        score = pvs(pos, td, alpha, beta, depth, false);
+        score = pvs(pos, td, alpha, beta, *depth, false);
 
         if td.stop_is_set() {
             return 0;
@@ -162,11 +253,15 @@ fn aspiration(
         if score <= alpha {
             beta = (alpha + beta) / 2;
             alpha = (-Score::MAX).max(alpha - delta);
-            depth = max_depth;
+            *depth = max_depth;
         } else if score >= beta {
             beta = Score::MAX.min(beta + delta);
             *best_move = td.best_move;
-            depth -= 1;
+
+            // a mate search has to prove its result at full depth
+            if !td.mate_search {
+                *depth = (*depth - 1).max(1);
+            }
         } else {
             return score;
         }
@@ -179,12 +274,19 @@ fn qs(pos: &Position, td: &mut ThreadData, mut alpha: i32, beta: i32) -> i32 {
     td.seldepth = td.seldepth.max(td.ply);
 
     let hash = pos.hash();
-    let mut eval = td
-        .chtable
-        .correct_evaluation(pos, pos.eval(&mut td.eval_cache));
+    let tt_entry = td.tt.probe(hash, td.ply);
+    let tt_pv = tt_entry.is_some_and(|entry| entry.was_pv());
+
+    // static eval is stored in the hash table
+    let raw_eval = match tt_entry {
+        Some(entry) => entry.eval(),
+        None => pos.eval(&mut td.accs, td.ply),
+    };
+
+    let mut eval = td.chtable.correct_evaluation(pos, raw_eval);
 
-    // probe hash table for cutoff
-    if let Some(entry) = td.tt.probe(hash, td.ply) {
+    // probe hash table for cutoff, entries can't be trusted to prove mates
+    if let Some(entry) = tt_entry.filter(|_| !td.mate_search) {
         let tt_score = entry.score();
         let bound = entry.bound();
         if match bound {
@@ -215,7 +317,7 @@ fn qs(pos: &Position, td: &mut ThreadData, mut alpha: i32, beta: i32) -> i32 {
 
     caps.iter()
         .enumerate()
-        .for_each(|(i, &cap)| scores[i] = mvv_lva(cap, pos));
+        .for_each(|(i, &cap)| scores[i] = capture_score(cap, pos, td));
 
     let mut best_move = Move::NULL;
     let mut bound = Bound::UPPER;
@@ -237,6 +339,7 @@ fn qs(pos: &Position, td: &mut ThreadData, mut alpha: i32, beta: i32) -> i32 {
         }
 
         td.qnodes += 1;
+        td.accs.push(td.ply, pos.delta(mov, &td.castling));
 
         let score = -qs(&new, td, -beta, -alpha);
 
@@ -257,7 +360,8 @@ fn qs(pos: &Position, td: &mut ThreadData, mut alpha: i32, beta: i32) -> i32 {
 
     td.ply -= 1;
 
-    td.tt.push(hash, best_move, 0, bound, eval, td.ply);
+    td.tt
+        .push(hash, best_move, 0, bound, eval, td.ply, raw_eval, tt_pv);
 
     eval
 }
@@ -275,15 +379,21 @@ fn pvs(
         return 0;
     }
 
-    if td.nodes & 1023 == 0 {
-        DISPLAY_NODES.fetch_add(1024, Relaxed);
+    if td.nodes() - td.flushed_nodes >= 1024 {
+        td.flush_nodes();
 
-        if td.timer() >= td.max_time || td.nodes() >= td.max_nodes {
+        if !td.check_pondering() && td.time_used() >= td.max_time {
             td.store_stop(true);
             return 0;
         }
     }
 
+    // hard node limit, counted across all threads and only checked when one is set
+    if td.max_nodes != u64::MAX && td.total_nodes() >= td.max_nodes {
+        td.store_stop(true);
+        return 0;
+    }
+
     let hash = pos.hash();
     let is_root = td.ply == 0;
     let in_check = td.plied[td.ply].in_check;
@@ -317,7 +427,16 @@ fn pvs(
     let s_mov = td.plied[td.ply].singular;
     let singular = s_mov != Move::NULL;
     let pc_beta = beta + 256;
-    let mut static_eval = pos.eval(&mut td.eval_cache);
+    let tt_entry = td.tt.probe(hash, td.ply);
+    let tt_pv = pv_node || tt_entry.is_some_and(|entry| entry.was_pv());
+
+    // static eval is stored in the hash table
+    let raw_eval = match tt_entry {
+        Some(entry) => entry.eval(),
+        None => pos.eval(&mut td.accs, td.ply),
+    };
+
+    let mut static_eval = raw_eval;
 
     if !singular {
         static_eval = td.chtable.correct_evaluation(pos, static_eval);
@@ -326,11 +445,11 @@ fn pvs(
     let mut eval = static_eval;
     let mut tt_move = Move::NULL;
     let mut tt_score = -Score::MAX;
-    let mut try_singular = !is_root && !singular && depth >= 8;
+    let mut try_singular = !is_root && !singular && !td.mate_search && depth >= 8;
     let mut can_probcut = true;
 
     // probing hash table
-    if let Some(entry) = td.tt.probe(hash, td.ply) {
+    if let Some(entry) = tt_entry {
         let bound = entry.bound();
         let depth_cond = entry.depth() >= depth - 3;
 
@@ -342,6 +461,7 @@ fn pvs(
         // tt cutoffs
         if !singular
             && !pv_node
+            && !td.mate_search
             && depth <= entry.depth()
             && match bound {
                 Bound::LOWER => tt_score >= beta,
@@ -365,7 +485,7 @@ fn pvs(
     let improving = td.ply > 1 && static_eval > td.plied[td.ply - 2].eval;
 
     // pruning
-    let can_prune = !pv_node && !in_check;
+    let can_prune = !pv_node && !in_check && !td.mate_search;
     if can_prune && beta.abs() < Score::MATE {
         // reverse futility pruning
         let improving_divisor = if improving { 2 } else { 1 };
@@ -400,6 +520,7 @@ fn pvs(
 
             let mut new = *pos;
             new.make_null();
+            td.accs.push(td.ply, Delta::default());
 
             let nw = -pvs(&new, td, -beta, -alpha, depth - r, false);
 
@@ -425,7 +546,7 @@ fn pvs(
     }
 
     // internal iterative reduction
-    depth -= i32::from(depth >= iir_depth() && tt_move == Move::NULL);
+    depth -= i32::from(depth >= iir_depth() && tt_move == Move::NULL && !td.mate_search);
 
     // probcut
     if can_prune && depth > pc_depth() && beta.abs() < Score::MATE && can_probcut {
@@ -453,6 +574,7 @@ fn pvs(
             }
 
             td.nodes += 1;
+            td.accs.push(td.ply, pos.delta(mov, &td.castling));
 
             let mut pc_score = -qs(&new, td, -pc_beta, -pc_beta + 1);
 
@@ -462,8 +584,16 @@ fn pvs(
 
             if pc_score >= pc_beta {
                 td.pop();
-                td.tt
-                    .push(hash, mov, depth as i8 - 3, Bound::LOWER, pc_beta, td.ply);
+                td.tt.push(
+                    hash,
+                    mov,
+                    depth as i8 - 3,
+                    Bound::LOWER,
+                    pc_beta,
+                    td.ply,
+                    raw_eval,
+                    tt_pv,
+                );
 
                 return pc_beta;
             }
@@ -486,10 +616,13 @@ fn pvs(
     for (i, &mov) in moves.iter().enumerate() {
         scores[i] = if mov == tt_move {
             MoveScore::HASH
-        } else if mov.is_en_passant() {
-            MoveScore::CAPTURE + 16
         } else if mov.is_capture() {
-            MoveScore::CAPTURE * i32::from(pos.see(mov, 0)) + mvv_lva(mov, pos)
+            // history only orders captures within the winning capture band
+            if pos.see(mov, 0) {
+                MoveScore::CAPTURE + capture_score(mov, pos, td)
+            } else {
+                mvv_lva(mov, pos)
+            }
         } else if mov.is_promo() {
             MoveScore::PROMO + i32::from(mov.flag() & 7)
         } else if mov == killer {
@@ -502,10 +635,15 @@ fn pvs(
     let mut legal = 0;
     let mut bound = Bound::UPPER;
     let mut best_score = -Score::MAX;
-    let mut best_move = tt_move;
+    let mut best_move = if is_root && td.skip_root_move(tt_move) {
+        Move::NULL
+    } else {
+        tt_move
+    };
     let mut quiets_tried = MoveList::ZEROED;
+    let mut captures_tried = MoveList::ZEROED;
 
-    let can_lmr = depth > 1 && !in_check;
+    let can_lmr = depth > 1 && !in_check && !td.mate_search;
     let lmr_base = f64::from(lmr_base()) / 100.0;
     let lmr_depth = (depth as f64).ln() / (f64::from(lmr_divisor()) / 100.0);
     let can_fp = !singular && depth < 6;
@@ -521,6 +659,11 @@ fn pvs(
             continue;
         }
 
+        // move was already reported in a previous pv line
+        if is_root && td.skip_root_move(mov) {
+            continue;
+        }
+
         // pre-move pruning
         if can_prune && best_score.abs() < Score::MATE {
             // late move pruning
@@ -567,6 +710,8 @@ fn pvs(
 
         if !mov.is_noisy() {
             quiets_tried.add(mov);
+        } else if mov.is_capture() {
+            captures_tried.add(mov);
         }
 
         let mut extend = 0;
@@ -627,6 +772,7 @@ fn pvs(
         let pre_nodes = td.nodes();
         td.plied[td.ply].played = mov;
         td.plied[td.ply].in_check = new_in_check;
+        td.accs.push(td.ply, pos.delta(mov, &td.castling));
 
         // pvs
         let score = if legal == 1 {
@@ -672,16 +818,32 @@ fn pvs(
         bound = Bound::LOWER;
         td.plied[td.ply - 1].cutoffs += 1;
 
+        if td.stop_is_set() {
+            break;
+        }
+
+        let bonus = hist_bonus_max().min(hist_bonus_mul() * depth - hist_bonus_offset());
+        let malus = hist_malus_max().min(hist_malus_mul() * depth - hist_malus_offset());
+
+        // captures tried before the cutoff are penalised whatever caused it
+        for &cap in captures_tried.iter().filter(|&&cap| cap != mov) {
+            let captured = captured_pc(cap, pos);
+            td.caphtable.push(pos.stm(), cap, captured, -malus);
+        }
+
+        if mov.is_capture() {
+            let captured = captured_pc(mov, pos);
+            td.caphtable.push(pos.stm(), mov, captured, bonus);
+        }
+
         // quiet cutoffs pushed to tables
-        if mov.is_noisy() || td.stop_is_set() {
+        if mov.is_noisy() {
             break;
         }
 
         td.plied.push_killer(mov, td.ply);
 
         if quiets_tried.len() > 1 || depth > 2 {
-            let bonus = hist_bonus_max().min(hist_bonus_mul() * depth - hist_bonus_offset());
-            let malus = hist_malus_max().min(hist_malus_mul() * depth - hist_malus_offset());
             td.htable.push(mov, prevs, pos.stm(), bonus, threats);
 
             for &quiet in quiets_tried.iter().take(quiets_tried.len() - 1) {
@@ -720,9 +882,19 @@ fn pvs(
             .update_correction_history(pos, depth, best_score - static_eval);
     }
 
-    // push new entry to hash table
-    td.tt
-        .push(hash, best_move, depth as i8, bound, best_score, td.ply);
+    // push new entry to hash table, unless root moves were excluded or restricted
+    if !is_root || (td.root_excluded.is_empty() && td.root_moves.is_empty()) {
+        td.tt.push(
+            hash,
+            best_move,
+            depth as i8,
+            bound,
+            best_score,
+            td.ply,
+            raw_eval,
+            tt_pv,
+        );
+    }
 
     best_score
 }
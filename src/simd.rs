@@ -0,0 +1,395 @@
+use std::sync::OnceLock;
+
+use crate::network::{Accumulator, HIDDEN, QA};
+
+type Flatten = unsafe fn(&Accumulator, &Accumulator) -> i32;
+// source and destination may be the same accumulator
+type Update = unsafe fn(*const Accumulator, *mut Accumulator, &[u16], &[u16], &[Accumulator]);
+
+#[derive(Clone, Copy)]
+pub struct Kernels {
+    pub name: &'static str,
+    flatten: Flatten,
+    update: Update,
+}
+
+impl Kernels {
+    pub fn flatten(&self, acc: &Accumulator, weights: &Accumulator) -> i32 {
+        // only kernels supported by the cpu are ever constructed
+        unsafe { (self.flatten)(acc, weights) }
+    }
+
+    pub fn update(
+        &self,
+        acc: &mut Accumulator,
+        adds: &[u16],
+        subs: &[u16],
+        weights: &[Accumulator],
+    ) {
+        let ptr: *mut Accumulator = acc;
+        unsafe { (self.update)(ptr, ptr, adds, subs, weights) }
+    }
+
+    pub fn update_from(
+        &self,
+        src: &Accumulator,
+        dst: &mut Accumulator,
+        adds: &[u16],
+        subs: &[u16],
+        weights: &[Accumulator],
+    ) {
+        unsafe { (self.update)(src, dst, adds, subs, weights) }
+    }
+
+    // every kernel supported by this cpu, fastest first
+    pub fn available() -> Vec<Self> {
+        let mut kernels = Vec::new();
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw") {
+                kernels.push(Self::new("avx512", avx512::flatten, avx512::update));
+            }
+
+            if is_x86_feature_detected!("avx2") {
+                kernels.push(Self::new("avx2", avx2::flatten, avx2::update));
+            }
+
+            if is_x86_feature_detected!("sse4.1") {
+                kernels.push(Self::new("sse4.1", sse41::flatten, sse41::update));
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            kernels.push(Self::new("neon", neon::flatten, neon::update));
+        }
+
+        kernels.push(Self::new("fallback", fallback::flatten, fallback::update));
+
+        kernels
+    }
+
+    fn new(name: &'static str, flatten: Flatten, update: Update) -> Self {
+        Self {
+            name,
+            flatten,
+            update,
+        }
+    }
+}
+
+pub fn kernels() -> &'static Kernels {
+    static KERNELS: OnceLock<Kernels> = OnceLock::new();
+    KERNELS.get_or_init(|| Kernels::available()[0])
+}
+
+// shared register-blocked accumulator update, parametrised over vector width
+macro_rules! update_kernel {
+    ($feature:literal, $vec:ty, $lanes:expr, $load:ident, $store:ident, $add:ident, $sub:ident) => {
+        #[target_feature(enable = $feature)]
+        pub unsafe fn update(
+            src: *const Accumulator,
+            dst: *mut Accumulator,
+            adds: &[u16],
+            subs: &[u16],
+            weights: &[Accumulator],
+        ) {
+            const REGS: usize = 8;
+            const PER: usize = REGS * $lanes;
+
+            let mut regs: [$vec; REGS] = std::mem::zeroed();
+
+            for i in 0..HIDDEN / PER {
+                let offset = PER * i;
+                let input = (*src).vals.as_ptr().add(offset);
+                let vals = (*dst).vals.as_mut_ptr().add(offset);
+
+                for (j, reg) in regs.iter_mut().enumerate() {
+                    *reg = $load(input.add(j * $lanes).cast());
+                }
+
+                for &add in adds {
+                    let row = weights[usize::from(add)].vals.as_ptr().add(offset);
+
+                    for (j, reg) in regs.iter_mut().enumerate() {
+                        *reg = $add(*reg, $load(row.add(j * $lanes).cast()));
+                    }
+                }
+
+                for &sub in subs {
+                    let row = weights[usize::from(sub)].vals.as_ptr().add(offset);
+
+                    for (j, reg) in regs.iter_mut().enumerate() {
+                        *reg = $sub(*reg, $load(row.add(j * $lanes).cast()));
+                    }
+                }
+
+                for (j, reg) in regs.iter().enumerate() {
+                    $store(vals.add(j * $lanes).cast(), *reg);
+                }
+            }
+        }
+    };
+}
+
+mod fallback {
+    use super::{Accumulator, HIDDEN, QA};
+
+    #[inline]
+    fn screlu(x: i16) -> i32 {
+        i32::from(x.clamp(0, QA as i16)).pow(2)
+    }
+
+    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
+        let mut sum = 0;
+
+        for (&x, &w) in acc.vals.iter().zip(&weights.vals) {
+            sum += screlu(x) * i32::from(w);
+        }
+
+        sum
+    }
+
+    pub unsafe fn update(
+        src: *const Accumulator,
+        dst: *mut Accumulator,
+        adds: &[u16],
+        subs: &[u16],
+        weights: &[Accumulator],
+    ) {
+        const REGS: usize = 8;
+        const PER: usize = REGS * 16;
+
+        let mut regs = [0i16; PER];
+
+        for i in 0..HIDDEN / PER {
+            let offset = PER * i;
+
+            for (j, reg) in regs.iter_mut().enumerate() {
+                *reg = (*src).vals[offset + j];
+            }
+
+            for &add in adds {
+                let row = &weights[usize::from(add)];
+
+                for (j, reg) in regs.iter_mut().enumerate() {
+                    *reg += row.vals[offset + j];
+                }
+            }
+
+            for &sub in subs {
+                let row = &weights[usize::from(sub)];
+
+                for (j, reg) in regs.iter_mut().enumerate() {
+                    *reg -= row.vals[offset + j];
+                }
+            }
+
+            for (j, reg) in regs.iter().enumerate() {
+                (*dst).vals[offset + j] = *reg;
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod sse41 {
+    use super::{Accumulator, HIDDEN, QA};
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
+        const CHUNK: usize = 8;
+
+        let mut sum = _mm_setzero_si128();
+        let min = _mm_setzero_si128();
+        let max = _mm_set1_epi16(QA as i16);
+
+        for i in 0..HIDDEN / CHUNK {
+            let mut v = _mm_load_si128(acc.vals.as_ptr().add(i * CHUNK).cast());
+            v = _mm_min_epi16(_mm_max_epi16(v, min), max);
+            let w = _mm_load_si128(weights.vals.as_ptr().add(i * CHUNK).cast());
+            let product = _mm_madd_epi16(v, _mm_mullo_epi16(v, w));
+            sum = _mm_add_epi32(sum, product);
+        }
+
+        let upper_64 = _mm_unpackhi_epi64(sum, sum);
+        let sum_64 = _mm_add_epi32(upper_64, sum);
+        let upper_32 = _mm_shuffle_epi32::<0b00_00_00_01>(sum_64);
+        let sum_32 = _mm_add_epi32(upper_32, sum_64);
+
+        _mm_cvtsi128_si32(sum_32)
+    }
+
+    update_kernel!(
+        "sse4.1",
+        __m128i,
+        8,
+        _mm_load_si128,
+        _mm_store_si128,
+        _mm_add_epi16,
+        _mm_sub_epi16
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use super::{Accumulator, HIDDEN, QA};
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
+        const CHUNK: usize = 16;
+
+        let mut sum = _mm256_setzero_si256();
+        let min = _mm256_setzero_si256();
+        let max = _mm256_set1_epi16(QA as i16);
+
+        for i in 0..HIDDEN / CHUNK {
+            let mut v = _mm256_load_si256(acc.vals.as_ptr().add(i * CHUNK).cast());
+            v = _mm256_min_epi16(_mm256_max_epi16(v, min), max);
+            let w = _mm256_load_si256(weights.vals.as_ptr().add(i * CHUNK).cast());
+            let product = _mm256_madd_epi16(v, _mm256_mullo_epi16(v, w));
+            sum = _mm256_add_epi32(sum, product);
+        }
+
+        let upper_128 = _mm256_extracti128_si256::<1>(sum);
+        let lower_128 = _mm256_castsi256_si128(sum);
+        let sum_128 = _mm_add_epi32(upper_128, lower_128);
+        let upper_64 = _mm_unpackhi_epi64(sum_128, sum_128);
+        let sum_64 = _mm_add_epi32(upper_64, sum_128);
+        let upper_32 = _mm_shuffle_epi32::<0b00_00_00_01>(sum_64);
+        let sum_32 = _mm_add_epi32(upper_32, sum_64);
+
+        _mm_cvtsi128_si32(sum_32)
+    }
+
+    update_kernel!(
+        "avx2",
+        __m256i,
+        16,
+        _mm256_load_si256,
+        _mm256_store_si256,
+        _mm256_add_epi16,
+        _mm256_sub_epi16
+    );
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx512 {
+    use super::{Accumulator, HIDDEN, QA};
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
+        const CHUNK: usize = 32;
+
+        let mut sum = _mm512_setzero_si512();
+        let min = _mm512_setzero_si512();
+        let max = _mm512_set1_epi16(QA as i16);
+
+        for i in 0..HIDDEN / CHUNK {
+            let mut v = _mm512_load_si512(acc.vals.as_ptr().add(i * CHUNK).cast());
+            v = _mm512_min_epi16(_mm512_max_epi16(v, min), max);
+            let w = _mm512_load_si512(weights.vals.as_ptr().add(i * CHUNK).cast());
+            let product = _mm512_madd_epi16(v, _mm512_mullo_epi16(v, w));
+            sum = _mm512_add_epi32(sum, product);
+        }
+
+        _mm512_reduce_add_epi32(sum)
+    }
+
+    update_kernel!(
+        "avx512f,avx512bw",
+        __m512i,
+        32,
+        _mm512_load_si512,
+        _mm512_store_si512,
+        _mm512_add_epi16,
+        _mm512_sub_epi16
+    );
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::{Accumulator, HIDDEN, QA};
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn flatten(acc: &Accumulator, weights: &Accumulator) -> i32 {
+        const CHUNK: usize = 8;
+
+        let mut sum = vdupq_n_s32(0);
+        let min = vdupq_n_s16(0);
+        let max = vdupq_n_s16(QA as i16);
+
+        for i in 0..HIDDEN / CHUNK {
+            let mut v = vld1q_s16(acc.vals.as_ptr().add(i * CHUNK));
+            v = vminq_s16(vmaxq_s16(v, min), max);
+            let w = vld1q_s16(weights.vals.as_ptr().add(i * CHUNK));
+            let vw = vmulq_s16(v, w);
+            sum = vmlal_s16(sum, vget_low_s16(v), vget_low_s16(vw));
+            sum = vmlal_high_s16(sum, v, vw);
+        }
+
+        vaddvq_s32(sum)
+    }
+
+    update_kernel!("neon", int16x8_t, 8, vld1q_s16, vst1q_s16, vaddq_s16, vsubq_s16);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // checks every available kernel against the fallback on random inputs
+    #[test]
+    fn kernels_match_fallback() {
+        let mut seed = 0x5EED_u64;
+        let mut rand = |range: i32| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % (2 * range as u64 + 1)) as i32 - range
+        };
+
+        let mut random_acc = |range: i32| {
+            let mut acc = Accumulator::default();
+            acc.vals.iter_mut().for_each(|x| *x = rand(range) as i16);
+            acc
+        };
+
+        let weights = (0..64).map(|_| random_acc(127)).collect::<Vec<_>>();
+        let accs = (0..64).map(|_| random_acc(400)).collect::<Vec<_>>();
+
+        let fallback = Kernels::new("fallback", fallback::flatten, fallback::update);
+
+        for kernel in Kernels::available() {
+            for (i, acc) in accs.iter().enumerate() {
+                let flattened = kernel.flatten(acc, &weights[i]);
+                assert_eq!(
+                    flattened,
+                    fallback.flatten(acc, &weights[i]),
+                    "{}",
+                    kernel.name
+                );
+
+                let adds = [i as u16, (i as u16 * 7) % 64];
+                let subs = [(i as u16 * 13) % 64, (i as u16 * 29) % 64];
+
+                let mut expected = *acc;
+                let mut actual = *acc;
+                fallback.update(&mut expected, &adds, &subs, &weights);
+                kernel.update(&mut actual, &adds, &subs, &weights);
+
+                assert_eq!(expected.vals, actual.vals, "{}", kernel.name);
+
+                let mut copied = Accumulator::default();
+                kernel.update_from(acc, &mut copied, &adds, &subs, &weights);
+
+                assert_eq!(expected.vals, copied.vals, "{}", kernel.name);
+            }
+        }
+    }
+}
@@ -1,8 +1,15 @@
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering::Relaxed};
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering::Relaxed},
+};
 
 use crate::{
-    consts::{CorrectionHistory, MoveScore, Score},
+    consts::{CorrectionHistory, MoveScore, Score, ZobristVals},
     moves::{Move, MoveList},
+    network::Network,
     position::Position,
     util::boxed_and_zeroed,
 };
@@ -28,9 +35,8 @@ impl<'a> HashView<'a> {
         unsafe {
             use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
 
-            let idx = (hash as usize) & (self.table.table.len() - 1);
-            let entry = &self.table.table[idx];
-            let ptr = entry as *const HashEntryInternal;
+            let cluster = &self.table.table[self.table.index(hash)];
+            let ptr = cluster as *const HashCluster;
 
             _mm_prefetch::<_MM_HINT_T0>(ptr.cast());
         }
@@ -40,11 +46,12 @@ impl<'a> HashView<'a> {
 #[derive(Clone, Copy, Default)]
 #[repr(C)]
 pub struct HashEntry {
-    key: u16,
     best_move: u16,
     score: i16,
+    eval: i16,
     depth: i8,
-    bound: u8,
+    // age << 3 | was pv << 2 | bound
+    flags: u8,
 }
 
 impl HashEntry {
@@ -53,60 +60,238 @@ impl HashEntry {
     }
 
     pub fn bound(&self) -> u8 {
-        self.bound & 3
+        self.flags & 3
+    }
+
+    pub fn was_pv(&self) -> bool {
+        self.flags & 4 > 0
+    }
+
+    fn age(&self) -> u8 {
+        self.flags >> 3
     }
 
     pub fn score(&self) -> i32 {
         i32::from(self.score)
     }
 
+    // raw static eval, before correction history
+    pub fn eval(&self) -> i32 {
+        i32::from(self.eval)
+    }
+
     pub fn best_move(&self, pos: &Position) -> Move {
         Move::from_short(self.best_move, pos)
     }
 
-    pub fn from_internal(atom: HashEntryInternal) -> Self {
-        unsafe { std::mem::transmute(atom.data.load(Relaxed)) }
+    fn from_u64(data: u64) -> Self {
+        unsafe { std::mem::transmute(data) }
     }
 
-    pub fn to_u64(self) -> u64 {
+    fn to_u64(self) -> u64 {
         unsafe { std::mem::transmute(self) }
     }
 }
 
+// key is stored xored with the data, so that an entry torn
+// by concurrent writes from different threads reads as a miss
 #[derive(Default)]
-pub struct HashEntryInternal {
+struct HashEntryInternal {
+    key: AtomicU64,
     data: AtomicU64,
 }
 
-impl Clone for HashEntryInternal {
-    fn clone(&self) -> Self {
+impl HashEntryInternal {
+    fn load(&self) -> (u64, HashEntry) {
+        let data = self.data.load(Relaxed);
+        let key = self.key.load(Relaxed) ^ data;
+        (key, HashEntry::from_u64(data))
+    }
+
+    fn store(&self, key: u64, entry: HashEntry) {
+        let data = entry.to_u64();
+        self.data.store(data, Relaxed);
+        self.key.store(key ^ data, Relaxed);
+    }
+}
+
+const CLUSTER_SIZE: usize = 4;
+const AGE_MASK: u8 = 31;
+
+#[derive(Default)]
+#[repr(C, align(64))]
+struct HashCluster {
+    entries: [HashEntryInternal; CLUSTER_SIZE],
+}
+
+// saved tables are a fixed size header followed by the entries as stored,
+// all values are little-endian
+const HASH_MAGIC: [u8; 8] = *b"akimbott";
+const HASH_VERSION: u32 = 1;
+const HASH_HEADER_SIZE: usize = 48;
+
+pub enum HashFileError {
+    Io(std::io::Error),
+    Missing,
+    Version(u32),
+    Layout,
+    Zobrist,
+    Network,
+    Size(u64),
+    Length(u64),
+}
+
+impl std::fmt::Display for HashFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{err}"),
+            Self::Missing => write!(f, "not a saved hash table"),
+            Self::Version(x) => {
+                write!(f, "unsupported format version {x}, expected {HASH_VERSION}")
+            }
+            Self::Layout => write!(f, "table uses a different entry layout"),
+            Self::Zobrist => write!(f, "table was saved with different zobrist keys"),
+            Self::Network => write!(f, "table was saved with a different network"),
+            Self::Size(clusters) => {
+                let mb = (clusters * std::mem::size_of::<HashCluster>() as u64) >> 20;
+                write!(
+                    f,
+                    "table was saved with Hash {mb}, set the same size to load it"
+                )
+            }
+            Self::Length(len) => write!(f, "file is {len} bytes, it is truncated or corrupted"),
+        }
+    }
+}
+
+pub struct HashStats {
+    pub entries: usize,
+    pub used: usize,
+    pub was_pv: usize,
+    pub depths: [usize; 128],
+    pub bounds: [usize; 3],
+    // indexed by number of searches since the entry was written
+    pub ages: [usize; AGE_MASK as usize + 1],
+}
+
+// memory for the hash table, allocated so that it can be backed by huge pages
+struct Clusters {
+    ptr: NonNull<HashCluster>,
+    len: usize,
+    layout: Layout,
+}
+
+// clusters only contain atomics
+unsafe impl Send for Clusters {}
+unsafe impl Sync for Clusters {}
+
+impl Default for Clusters {
+    fn default() -> Self {
         Self {
-            data: AtomicU64::new(self.data.load(Relaxed)),
+            ptr: NonNull::dangling(),
+            len: 0,
+            layout: Layout::new::<()>(),
+        }
+    }
+}
+
+impl Drop for Clusters {
+    fn drop(&mut self) {
+        if self.layout.size() > 0 {
+            unsafe { alloc::dealloc(self.ptr.as_ptr().cast(), self.layout) }
         }
     }
 }
 
+impl std::ops::Deref for Clusters {
+    type Target = [HashCluster];
+    fn deref(&self) -> &Self::Target {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl std::ops::DerefMut for Clusters {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Clusters {
+    // uninitialised, also returns whether huge pages were used
+    unsafe fn alloc(len: usize) -> (Self, bool) {
+        let (ptr, layout, huge) = match Self::alloc_huge(len) {
+            Some((ptr, layout)) => (ptr, layout, true),
+            None => {
+                let layout = Layout::array::<HashCluster>(len).unwrap();
+                (alloc::alloc(layout), layout, false)
+            }
+        };
+
+        let Some(ptr) = NonNull::new(ptr.cast()) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        (Self { ptr, len, layout }, huge)
+    }
+
+    // transparent huge pages need the table aligned to the huge page size
+    #[cfg(target_os = "linux")]
+    unsafe fn alloc_huge(len: usize) -> Option<(*mut u8, Layout)> {
+        const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+        const MADV_HUGEPAGE: i32 = 14;
+
+        extern "C" {
+            fn madvise(addr: *mut std::ffi::c_void, len: usize, advice: i32) -> i32;
+        }
+
+        let size = len * std::mem::size_of::<HashCluster>();
+        let layout = Layout::from_size_align(size, HUGE_PAGE_SIZE).ok()?;
+        let ptr = alloc::alloc(layout);
+
+        if ptr.is_null() {
+            return None;
+        }
+
+        if madvise(ptr.cast(), size, MADV_HUGEPAGE) != 0 {
+            alloc::dealloc(ptr, layout);
+            return None;
+        }
+
+        Some((ptr, layout))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    unsafe fn alloc_huge(_: usize) -> Option<(*mut u8, Layout)> {
+        None
+    }
+}
+
 #[derive(Default)]
 pub struct HashTable {
-    table: Vec<HashEntryInternal>,
+    table: Clusters,
     age: AtomicU8,
 }
 
 impl HashTable {
-    pub fn resize(&mut self, size: usize, threads: usize) {
+    // returns whether the table is backed by huge pages
+    pub fn resize(&mut self, size: usize, threads: usize) -> bool {
+        use std::mem::size_of;
+
         self.age.store(0, Relaxed);
 
-        let num_entries = 1 << (80 - (size as u64).leading_zeros());
-        let chunk_size = num_entries / threads + 1;
+        // largest power of two number of clusters that fits in `size` MB
+        let bytes = 1 << (83 - (size as u64).leading_zeros());
+        let num_clusters = bytes / size_of::<HashCluster>();
+        let chunk_size = bytes / threads + 1;
 
-        self.table = Vec::new();
-        self.table.reserve_exact(num_entries);
+        // free the old table first
+        self.table = Clusters::default();
 
         unsafe {
-            use std::mem::{size_of, MaybeUninit};
-            let ptr = self.table.as_mut_ptr().cast();
-            let uninit: &mut [MaybeUninit<u8>] =
-                std::slice::from_raw_parts_mut(ptr, num_entries * size_of::<HashEntryInternal>());
+            use std::mem::MaybeUninit;
+            let (table, huge) = Clusters::alloc(num_clusters);
+            let ptr = table.ptr.as_ptr().cast();
+            let uninit: &mut [MaybeUninit<u8>] = std::slice::from_raw_parts_mut(ptr, bytes);
 
             std::thread::scope(|s| {
                 for chunk in uninit.chunks_mut(chunk_size) {
@@ -116,7 +301,8 @@ impl HashTable {
                 }
             });
 
-            self.table.set_len(num_entries);
+            self.table = table;
+            huge
         }
     }
 
@@ -128,8 +314,8 @@ impl HashTable {
         std::thread::scope(|s| {
             for chunk in self.table.chunks_mut(chunk_size) {
                 s.spawn(|| {
-                    for entry in chunk.iter_mut() {
-                        *entry = HashEntryInternal::default();
+                    for cluster in chunk.iter_mut() {
+                        *cluster = HashCluster::default();
                     }
                 });
             }
@@ -137,22 +323,64 @@ impl HashTable {
     }
 
     pub fn age_up(&self) {
-        self.age.store(63.min(self.get_age() + 1), Relaxed);
+        self.age.store((self.get_age() + 1) & AGE_MASK, Relaxed);
     }
 
     pub fn get_age(&self) -> u8 {
         self.age.load(Relaxed)
     }
 
-    pub fn push(&self, hash: u64, mov: Move, depth: i8, bound: u8, mut score: i32, ply: i32) {
-        let key = (hash >> 48) as u16;
-        let idx = (hash as usize) & (self.table.len() - 1);
-        let entry = HashEntry::from_internal(self.table[idx].clone());
+    fn index(&self, hash: u64) -> usize {
+        (hash as usize) & (self.table.len() - 1)
+    }
+
+    // number of searches since the entry was written
+    fn relative_age(&self, entry: &HashEntry) -> i32 {
+        i32::from(self.get_age().wrapping_sub(entry.age()) & AGE_MASK)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn push(
+        &self,
+        hash: u64,
+        mov: Move,
+        depth: i8,
+        bound: u8,
+        mut score: i32,
+        ply: i32,
+        eval: i32,
+        pv: bool,
+    ) {
+        let cluster = &self.table[self.index(hash)];
+
+        // replace the entry for this position if there is one,
+        // otherwise the one with the lowest depth, adjusted for age
+        let mut replace = &cluster.entries[0];
+        let mut worst = i32::MAX;
+
+        for slot in &cluster.entries {
+            let (key, entry) = slot.load();
+            let diff = self.relative_age(&entry);
 
-        // replacement scheme
-        let diff = self.get_age() - (entry.bound >> 2);
-        if ply > 0 && key == entry.key && depth as u8 + 2 * diff < entry.depth as u8 {
-            return;
+            if key == hash {
+                if ply > 0 && i32::from(depth) + 2 * diff < entry.depth() {
+                    return;
+                }
+
+                replace = slot;
+                break;
+            }
+
+            let value = if entry.to_u64() == 0 {
+                i32::MIN
+            } else {
+                entry.depth() - 2 * diff
+            };
+
+            if value < worst {
+                worst = value;
+                replace = slot;
+            }
         }
 
         // replace entry
@@ -161,27 +389,163 @@ impl HashTable {
         } else {
             0
         };
-        let best_move = mov.to_short();
+
         let new_entry = HashEntry {
-            key,
-            best_move,
+            best_move: mov.to_short(),
             score: score as i16,
+            eval: eval as i16,
             depth,
-            bound: (self.get_age() << 2) | bound,
+            flags: (self.get_age() << 3) | (u8::from(pv) << 2) | bound,
+        };
+
+        replace.store(hash, new_entry);
+    }
+
+    // permille of entries written during the current search, from a sample
+    pub fn hashfull(&self) -> usize {
+        let sample = self.table.iter().take(1000 / CLUSTER_SIZE);
+        let mut total = 0;
+        let mut used = 0;
+
+        for cluster in sample {
+            for slot in &cluster.entries {
+                let (_, entry) = slot.load();
+                total += 1;
+                used += usize::from(entry.to_u64() != 0 && self.relative_age(&entry) == 0);
+            }
         }
-        .to_u64();
 
-        self.table[idx].data.store(new_entry, Relaxed);
+        1000 * used / total.max(1)
     }
 
-    pub fn probe(&self, hash: u64, ply: i32) -> Option<HashEntry> {
-        let idx = (hash as usize) & (self.table.len() - 1);
-        let mut entry = HashEntry::from_internal(self.table[idx].clone());
+    // scans the whole table, for tuning hash sizes
+    pub fn stats(&self) -> HashStats {
+        let mut stats = HashStats {
+            entries: self.table.len() * CLUSTER_SIZE,
+            used: 0,
+            was_pv: 0,
+            depths: [0; 128],
+            bounds: [0; 3],
+            ages: [0; AGE_MASK as usize + 1],
+        };
 
-        if entry.key != (hash >> 48) as u16 {
-            return None;
+        for slot in self.table.iter().flat_map(|cluster| &cluster.entries) {
+            let (_, entry) = slot.load();
+
+            if entry.to_u64() == 0 {
+                continue;
+            }
+
+            stats.used += 1;
+            stats.was_pv += usize::from(entry.was_pv());
+            stats.depths[entry.depth().clamp(0, 127) as usize] += 1;
+            stats.bounds[usize::from(entry.bound()).min(2)] += 1;
+            stats.ages[self.relative_age(&entry) as usize] += 1;
         }
 
+        stats
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), HashFileError> {
+        let file = File::create(path).map_err(HashFileError::Io)?;
+        let mut out = BufWriter::new(file);
+
+        let mut header = [0; HASH_HEADER_SIZE];
+        header[..8].copy_from_slice(&HASH_MAGIC);
+        header[8..12].copy_from_slice(&HASH_VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(CLUSTER_SIZE as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&ZobristVals::fingerprint().to_le_bytes());
+        header[24..32].copy_from_slice(&(self.table.len() as u64).to_le_bytes());
+        header[32..40].copy_from_slice(&Network::checksum().to_le_bytes());
+        header[40] = self.get_age();
+
+        out.write_all(&header).map_err(HashFileError::Io)?;
+
+        for slot in self.table.iter().flat_map(|cluster| &cluster.entries) {
+            let key = slot.key.load(Relaxed).to_le_bytes();
+            let data = slot.data.load(Relaxed).to_le_bytes();
+            out.write_all(&key).map_err(HashFileError::Io)?;
+            out.write_all(&data).map_err(HashFileError::Io)?;
+        }
+
+        out.flush().map_err(HashFileError::Io)
+    }
+
+    // loads a table saved with the same size, checking it was saved by a compatible build
+    // and network, as entries store static evals
+    pub fn load(&mut self, path: &str) -> Result<(), HashFileError> {
+        let file = File::open(path).map_err(HashFileError::Io)?;
+        let len = file.metadata().map_err(HashFileError::Io)?.len();
+        let mut input = BufReader::new(file);
+
+        let mut header = [0; HASH_HEADER_SIZE];
+        input
+            .read_exact(&mut header)
+            .map_err(|_| HashFileError::Missing)?;
+
+        let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let read_u64 = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+
+        if header[..8] != HASH_MAGIC {
+            return Err(HashFileError::Missing);
+        }
+
+        if read_u32(8) != HASH_VERSION {
+            return Err(HashFileError::Version(read_u32(8)));
+        }
+
+        if read_u32(12) != CLUSTER_SIZE as u32 {
+            return Err(HashFileError::Layout);
+        }
+
+        if read_u64(16) != ZobristVals::fingerprint() {
+            return Err(HashFileError::Zobrist);
+        }
+
+        if read_u64(32) != Network::checksum() {
+            return Err(HashFileError::Network);
+        }
+
+        let clusters = read_u64(24);
+        let cluster_bytes = std::mem::size_of::<HashCluster>() as u64;
+
+        if clusters.checked_mul(cluster_bytes) != Some(len - HASH_HEADER_SIZE as u64) {
+            return Err(HashFileError::Length(len));
+        }
+
+        if clusters != self.table.len() as u64 {
+            return Err(HashFileError::Size(clusters));
+        }
+
+        let mut buf = [0; 16 * CLUSTER_SIZE];
+
+        for cluster in self.table.iter_mut() {
+            // don't leave a partially loaded table behind
+            if let Err(err) = input.read_exact(&mut buf) {
+                self.clear(1);
+                return Err(HashFileError::Io(err));
+            }
+
+            for (slot, bytes) in cluster.entries.iter_mut().zip(buf.chunks_exact(16)) {
+                *slot.key.get_mut() = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+                *slot.data.get_mut() = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+            }
+        }
+
+        self.age.store(header[40] & AGE_MASK, Relaxed);
+
+        Ok(())
+    }
+
+    pub fn probe(&self, hash: u64, ply: i32) -> Option<HashEntry> {
+        let cluster = &self.table[self.index(hash)];
+
+        let (_, mut entry) = cluster
+            .entries
+            .iter()
+            .map(HashEntryInternal::load)
+            .find(|&(key, _)| key == hash)?;
+
         entry.score -= if entry.score.abs() > Score::MATE as i16 {
             entry.score.signum() * ply as i16
         } else {
@@ -192,7 +556,29 @@ impl HashTable {
     }
 }
 
+// history tables made up of i32 values, shared between threads after a search
+pub trait HistoryValues {
+    fn values(&self) -> &[i32];
+
+    fn values_mut(&mut self) -> &mut [i32];
+
+    fn copy_from(&mut self, other: &Self) {
+        self.values_mut().copy_from_slice(other.values());
+    }
+
+    // sets every entry to its mean across this and the other tables
+    fn merge(&mut self, others: &[&Self]) {
+        let tables = others.len() as i32 + 1;
+
+        for (i, val) in self.values_mut().iter_mut().enumerate() {
+            let sum = others.iter().map(|other| other.values()[i]).sum::<i32>();
+            *val = (*val + sum) / tables;
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
+#[repr(C)]
 pub struct HistoryEntry {
     score: [[i32; 2]; 2],
     continuation: [[i32; 64]; 6],
@@ -220,6 +606,23 @@ impl Default for HistoryTable {
     }
 }
 
+impl HistoryValues for HistoryTable {
+    fn values(&self) -> &[i32] {
+        let entries = self.table.as_flattened().as_flattened();
+        let len = std::mem::size_of_val(entries) / std::mem::size_of::<i32>();
+
+        // entries are made up only of i32s
+        unsafe { std::slice::from_raw_parts(entries.as_ptr().cast(), len) }
+    }
+
+    fn values_mut(&mut self) -> &mut [i32] {
+        let entries = self.table.as_flattened_mut().as_flattened_mut();
+        let len = std::mem::size_of_val(entries) / std::mem::size_of::<i32>();
+
+        unsafe { std::slice::from_raw_parts_mut(entries.as_mut_ptr().cast(), len) }
+    }
+}
+
 fn threatened(sq: usize, threats: u64) -> usize {
     usize::from(threats & (1 << sq) > 0)
 }
@@ -258,6 +661,48 @@ impl HistoryTable {
     }
 }
 
+#[derive(Clone)]
+pub struct CaptureHistoryTable {
+    // [side][moved piece][to][captured piece]
+    table: Box<[[[[i32; 8]; 64]; 8]; 2]>,
+}
+
+impl Default for CaptureHistoryTable {
+    fn default() -> Self {
+        Self {
+            table: unsafe { boxed_and_zeroed() },
+        }
+    }
+}
+
+impl HistoryValues for CaptureHistoryTable {
+    fn values(&self) -> &[i32] {
+        self.table.as_flattened().as_flattened().as_flattened()
+    }
+
+    fn values_mut(&mut self) -> &mut [i32] {
+        self.table
+            .as_flattened_mut()
+            .as_flattened_mut()
+            .as_flattened_mut()
+    }
+}
+
+impl CaptureHistoryTable {
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn get_score(&self, side: usize, mov: Move, captured: usize) -> i32 {
+        self.table[side][mov.moved_pc()][mov.to()][captured]
+    }
+
+    pub fn push(&mut self, side: usize, mov: Move, captured: usize, bonus: i32) {
+        let entry = &mut self.table[side][mov.moved_pc()][mov.to()][captured];
+        *entry += bonus - *entry * bonus.abs() / MoveScore::HISTORY_MAX;
+    }
+}
+
 pub struct NodeTable {
     table: Box<[[u64; 64]; 64]>,
 }
@@ -271,6 +716,10 @@ impl Default for NodeTable {
 }
 
 impl NodeTable {
+    pub fn clear(&mut self) {
+        self.table.iter_mut().for_each(|row| row.fill(0));
+    }
+
     pub fn get(&self, mov: Move) -> u64 {
         self.table[mov.from()][mov.to()]
     }
@@ -355,6 +804,16 @@ impl Default for CorrectionHistoryTable {
     }
 }
 
+impl HistoryValues for CorrectionHistoryTable {
+    fn values(&self) -> &[i32] {
+        self.table.as_flattened()
+    }
+
+    fn values_mut(&mut self) -> &mut [i32] {
+        self.table.as_flattened_mut()
+    }
+}
+
 impl CorrectionHistoryTable {
     pub fn age_entries(&mut self) {
         self.table.iter_mut().flatten().for_each(|x| *x /= 2);
@@ -1,70 +1,169 @@
 use std::{
-    sync::atomic::{AtomicBool, Ordering::Relaxed},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        mpsc::{channel, Receiver, Sender},
+    },
     time::Instant,
 };
 
 use crate::{
+    consts::Score,
     frc::Castling,
-    moves::Move,
-    network::EvalTable,
+    moves::{Move, MoveList},
+    network::AccumulatorStack,
     position::Position,
-    tables::{CorrectionHistoryTable, HashTable, HashView, HistoryTable, NodeTable, PlyTable},
+    tables::{
+        CaptureHistoryTable, CorrectionHistoryTable, HashTable, HashView, HistoryTable,
+        HistoryValues, NodeTable, PlyTable,
+    },
 };
 
 pub struct ThreadData<'a> {
     // search control
     pub timing: Instant,
+    pub time_offset: u128,
     pub max_time: u128,
     pub max_nodes: u64,
     pub abort: &'a AtomicBool,
+    pub infinite: &'a AtomicBool,
+    pub shared_nodes: &'a AtomicU64,
+    pub pondering: bool,
     pub min_nmp_ply: i32,
+    pub mate_search: bool,
+    pub multi_pv: usize,
+    pub deterministic: bool,
+    pub root_excluded: MoveList,
+    pub root_moves: MoveList,
 
     // tables
     pub tt: HashView<'a>,
     pub htable: HistoryTable,
+    pub caphtable: CaptureHistoryTable,
     pub chtable: CorrectionHistoryTable,
     pub plied: PlyTable,
     pub ntable: NodeTable,
     pub stack: Vec<u64>,
     pub castling: Castling,
-    pub eval_cache: EvalTable,
+    pub accs: AccumulatorStack,
 
     // uci output
     pub nodes: u64,
     pub qnodes: u64,
+    pub flushed_nodes: u64,
     pub ply: i32,
     pub best_move: Move,
+    pub root_pv: MoveList,
     pub seldepth: i32,
+    pub completed_depth: i32,
+}
+
+// state owned by a search thread that is kept between searches
+#[derive(Default)]
+pub struct SearchTables {
+    pub htable: HistoryTable,
+    pub caphtable: CaptureHistoryTable,
+    pub chtable: CorrectionHistoryTable,
+    pub plied: PlyTable,
+    pub ntable: NodeTable,
+    pub accs: AccumulatorStack,
+}
+
+impl SearchTables {
+    pub fn clear(&mut self) {
+        self.htable.clear();
+        self.caphtable.clear();
+        self.chtable.clear();
+        self.clear_accumulators();
+    }
+
+    // cached accumulators are only valid for the network they were made with
+    pub fn clear_accumulators(&mut self) {
+        self.accs = AccumulatorStack::default();
+    }
 }
 
 impl<'a> ThreadData<'a> {
     pub fn new(
         abort: &'a AtomicBool,
+        infinite: &'a AtomicBool,
+        shared_nodes: &'a AtomicU64,
         tt: &'a HashTable,
         stack: Vec<u64>,
-        htable: HistoryTable,
-        chtable: CorrectionHistoryTable,
+        tables: SearchTables,
         castling: Castling,
     ) -> Self {
+        let SearchTables {
+            htable,
+            caphtable,
+            chtable,
+            plied,
+            ntable,
+            accs,
+        } = tables;
+
         Self {
             timing: Instant::now(),
+            time_offset: 0,
             max_time: 0,
             max_nodes: u64::MAX,
             min_nmp_ply: 0,
+            mate_search: false,
+            multi_pv: 1,
+            deterministic: false,
+            root_excluded: MoveList::ZEROED,
+            root_moves: MoveList::ZEROED,
             tt: HashView::new(tt),
             htable,
+            caphtable,
             chtable,
-            plied: PlyTable::default(),
-            ntable: NodeTable::default(),
+            plied,
+            ntable,
             stack,
             castling,
             nodes: 0,
             qnodes: 0,
+            flushed_nodes: 0,
             ply: 0,
             best_move: Move::NULL,
-            eval_cache: Default::default(),
+            root_pv: MoveList::ZEROED,
+            accs,
             seldepth: 0,
+            completed_depth: 0,
             abort,
+            infinite,
+            shared_nodes,
+            pondering: false,
+        }
+    }
+
+    pub fn result(&self, best_move: Move, score: i32) -> SearchResult {
+        // the pv from the last completed iteration may not start with the best move
+        // if a later iteration was stopped partway through
+        let pv = if self.root_pv.first() == Some(&best_move) {
+            self.root_pv
+        } else {
+            let mut pv = MoveList::ZEROED;
+            pv.add(best_move);
+            pv
+        };
+
+        SearchResult {
+            best_move,
+            score,
+            depth: self.completed_depth,
+            seldepth: self.seldepth,
+            pv,
+        }
+    }
+
+    pub fn into_tables(self) -> SearchTables {
+        SearchTables {
+            htable: self.htable,
+            caphtable: self.caphtable,
+            chtable: self.chtable,
+            plied: self.plied,
+            ntable: self.ntable,
+            accs: self.accs,
         }
     }
 
@@ -72,10 +171,35 @@ impl<'a> ThreadData<'a> {
         self.timing.elapsed().as_millis()
     }
 
+    pub fn time_used(&self) -> u128 {
+        self.timer() - self.time_offset
+    }
+
+    pub fn check_pondering(&mut self) -> bool {
+        // limits apply from the moment of a ponderhit
+        if self.pondering && !self.infinite.load(Relaxed) {
+            self.pondering = false;
+            self.time_offset = self.timer();
+        }
+
+        self.pondering
+    }
+
     pub fn nodes(&self) -> u64 {
         self.nodes + self.qnodes
     }
 
+    // nodes searched by every thread, including those this thread hasn't shared yet
+    pub fn total_nodes(&self) -> u64 {
+        self.shared_nodes.load(Relaxed) + self.nodes() - self.flushed_nodes
+    }
+
+    pub fn flush_nodes(&mut self) {
+        self.shared_nodes
+            .fetch_add(self.nodes() - self.flushed_nodes, Relaxed);
+        self.flushed_nodes = self.nodes();
+    }
+
     pub fn stop_is_set(&self) -> bool {
         self.abort.load(Relaxed)
     }
@@ -84,6 +208,11 @@ impl<'a> ThreadData<'a> {
         self.abort.store(val, Relaxed);
     }
 
+    pub fn skip_root_move(&self, mov: Move) -> bool {
+        let restricted = !self.root_moves.is_empty() && !self.root_moves.contains(&mov);
+        restricted || self.root_excluded.contains(&mov)
+    }
+
     pub fn repetition(&self, pos: &Position, curr_hash: u64, root: bool) -> bool {
         if self.stack.len() < 6 {
             return false;
@@ -116,3 +245,175 @@ impl<'a> ThreadData<'a> {
         self.ply -= 1;
     }
 }
+
+// what a thread found by the end of its search
+#[derive(Clone, Copy)]
+pub struct SearchResult {
+    pub best_move: Move,
+    pub score: i32,
+    pub depth: i32,
+    pub seldepth: i32,
+    pub pv: MoveList,
+}
+
+// each thread votes for its best move, weighted by its completed depth and how
+// its score compares to the other threads, returning the thread to report
+pub fn vote(results: &[SearchResult]) -> usize {
+    // workers that never finished an iteration only have a fallback move
+    let finished = || results.iter().filter(|res| res.depth > 0);
+
+    let min_score = finished().map(|res| res.score).min().unwrap_or(0);
+    let weight = |res: &SearchResult| i64::from(res.score - min_score + 14) * i64::from(res.depth);
+    let votes = |mov: Move| {
+        finished()
+            .filter(|res| res.best_move == mov)
+            .map(weight)
+            .sum::<i64>()
+    };
+
+    let mut best = 0;
+    for (idx, res) in results.iter().enumerate().skip(1) {
+        let chosen = &results[best];
+
+        if res.depth == 0 {
+            continue;
+        }
+
+        // the main thread can be stopped before finishing an iteration too
+        if chosen.depth == 0 {
+            best = idx;
+            continue;
+        }
+
+        // a proven mate is preferred over any vote, the quickest one first
+        if chosen.score >= Score::MATE || res.score >= Score::MATE {
+            if res.score > chosen.score {
+                best = idx;
+            }
+            continue;
+        }
+
+        if (votes(res.best_move), res.depth) > (votes(chosen.best_move), chosen.depth) {
+            best = idx;
+        }
+    }
+
+    best
+}
+
+type Job = Box<dyn FnOnce(SearchTables) -> SearchTables + Send>;
+
+struct Worker {
+    jobs: Sender<(Job, SearchTables)>,
+    results: Receiver<SearchTables>,
+}
+
+impl Worker {
+    fn spawn() -> Self {
+        let (jobs, job_receiver) = channel::<(Job, SearchTables)>();
+        let (result_sender, results) = channel();
+
+        // parked waiting for a job until the pool drops the sender
+        std::thread::spawn(move || {
+            for (job, tables) in job_receiver {
+                if result_sender.send(job(tables)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { jobs, results }
+    }
+}
+
+// search threads that live between searches, the first being the main thread
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    tables: Vec<SearchTables>,
+}
+
+impl Default for ThreadPool {
+    fn default() -> Self {
+        let mut pool = Self {
+            workers: Vec::new(),
+            tables: Vec::new(),
+        };
+
+        pool.resize(1);
+        pool
+    }
+}
+
+impl ThreadPool {
+    pub fn resize(&mut self, threads: usize) {
+        let threads = threads.max(1);
+        self.workers.truncate(threads);
+        self.tables.truncate(threads);
+
+        while self.workers.len() < threads {
+            self.workers.push(Worker::spawn());
+            self.tables.push(SearchTables::default());
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tables.iter_mut().for_each(SearchTables::clear);
+    }
+
+    pub fn clear_accumulators(&mut self) {
+        self.tables
+            .iter_mut()
+            .for_each(SearchTables::clear_accumulators);
+    }
+
+    // helpers learn from their own searches, so their histories are averaged
+    // with the main thread's and every thread starts the next search from that
+    pub fn merge_histories(&mut self) {
+        let Some((main, helpers)) = self.tables.split_first_mut() else {
+            return;
+        };
+
+        if helpers.is_empty() {
+            return;
+        }
+
+        let htables = helpers.iter().map(|t| &t.htable).collect::<Vec<_>>();
+        main.htable.merge(&htables);
+
+        let caphtables = helpers.iter().map(|t| &t.caphtable).collect::<Vec<_>>();
+        main.caphtable.merge(&caphtables);
+
+        let chtables = helpers.iter().map(|t| &t.chtable).collect::<Vec<_>>();
+        main.chtable.merge(&chtables);
+
+        for helper in helpers {
+            helper.htable.copy_from(&main.htable);
+            helper.caphtable.copy_from(&main.caphtable);
+            helper.chtable.copy_from(&main.chtable);
+        }
+    }
+
+    pub fn age_entries(&mut self) {
+        for tables in &mut self.tables {
+            tables.chtable.age_entries();
+        }
+    }
+
+    pub fn main_tables(&mut self) -> &mut SearchTables {
+        &mut self.tables[0]
+    }
+
+    // wakes each thread with the job made for it, which is handed that thread's tables
+    pub fn start(&mut self, mut job: impl FnMut(usize) -> Job) {
+        for (id, (worker, tables)) in self.workers.iter().zip(self.tables.drain(..)).enumerate() {
+            worker.jobs.send((job(id), tables)).unwrap();
+        }
+    }
+
+    // blocks until every thread has finished its job and parked again
+    pub fn wait(&mut self) {
+        for worker in &self.workers {
+            self.tables.push(worker.results.recv().unwrap());
+        }
+    }
+}
@@ -1,31 +1,68 @@
+use crate::bitloop;
+use crate::consts::{Piece, Rights, Score};
 use crate::frc::Castling;
+use crate::moves::MoveList;
+use crate::network::{Network, NUM_BUCKETS};
 use crate::position::Position;
-use crate::search::go;
-use crate::tables::{CorrectionHistoryTable, HashTable, HistoryTable};
-use crate::thread::ThreadData;
+use crate::search::{go, print_info};
+use crate::simd::kernels;
+use crate::tables::{CorrectionHistoryTable, HashTable};
+use crate::thread::{vote, ThreadData, ThreadPool};
 use crate::util::STARTPOS;
 
 #[cfg(feature = "tuning")]
 use crate::search::{list_params, print_params_ob, set_param};
 
-use std::{io, process, sync::atomic::AtomicBool, time::Instant};
+use std::{
+    io, process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+        mpsc::channel,
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 const FEN_STRING: &str = include_str!("../resources/fens.txt");
+const EMBEDDED_NET: &str = "<embedded>";
 
 pub fn run_uci() {
     // initialise engine
     let mut castling = Castling::default();
-    let mut pos = Position::from_fen(STARTPOS, &mut castling);
+    let mut pos = Position::from_fen(STARTPOS, &mut castling).unwrap();
     let mut stack = Vec::new();
-    let mut tt = HashTable::default();
-    let mut htable = HistoryTable::default();
-    let mut chtable = CorrectionHistoryTable::default();
+    let mut tt = Arc::new(HashTable::default());
+    let mut pool = ThreadPool::default();
     let mut threads = 1;
-    tt.resize(16, 1);
+    let mut multi_pv = 1;
+    let mut deterministic = false;
+    idle(&mut tt).resize(16, 1);
 
-    // bench for OpenBench
+    // bench for OpenBench, optionally with more threads to measure smp changes
     if let Some("bench") = std::env::args().nth(1).as_deref() {
-        run_bench(&tt, stack, &htable, &chtable);
+        let threads = std::env::args().nth(2).and_then(|x| x.parse().ok());
+        run_bench(threads.unwrap_or(1));
+        return;
+    }
+
+    // add a header to a raw network from the trainer
+    if let Some("pack") = std::env::args().nth(1).as_deref() {
+        let args = std::env::args().collect::<Vec<_>>();
+
+        // layer sizes are only given for networks with layers after the feature transformer
+        let layers = match args.get(4..) {
+            None | Some([]) => Some((0, 0)),
+            Some([l1, l2]) => l1.parse().ok().zip(l2.parse().ok()),
+            _ => None,
+        };
+
+        match (args.get(2..4), layers) {
+            (Some(paths), Some((l1, l2))) => match Network::pack(&paths[0], &paths[1], l1, l2) {
+                Ok(()) => println!("packed network to {}", args[3]),
+                Err(err) => println!("failed to pack network: {err}"),
+            },
+            _ => println!("usage: akimbo pack <raw network> <output> [<l1 size> <l2 size>]"),
+        }
         return;
     }
 
@@ -55,33 +92,73 @@ pub fn run_uci() {
             "uci" => preamble(),
             "isready" => println!("readyok"),
             "ucinewgame" => {
-                pos = Position::from_fen(STARTPOS, &mut castling);
-                tt.clear(threads);
-                htable.clear();
-                chtable.clear();
+                pos = Position::from_fen(STARTPOS, &mut castling).unwrap();
+                idle(&mut tt).clear(threads);
+                pool.clear();
             }
             "setoption" => match commands[..] {
-                ["setoption", "name", "Hash", "value", x] => tt.resize(x.parse().unwrap(), threads),
-                ["setoption", "name", "Clear", "Hash"] => tt.clear(threads),
-                ["setoption", "name", "Threads", "value", x] => threads = x.parse().unwrap(),
+                ["setoption", "name", "Hash", "value", x] => {
+                    let pages = if idle(&mut tt).resize(x.parse().unwrap(), threads) {
+                        "huge"
+                    } else {
+                        "normal"
+                    };
+
+                    println!("info string hash allocated with {pages} pages");
+                }
+                ["setoption", "name", "Clear", "Hash"] => idle(&mut tt).clear(threads),
+                ["setoption", "name", "Threads", "value", x] => {
+                    threads = x.parse().unwrap();
+                    pool.resize(threads);
+                }
+                ["setoption", "name", "MultiPV", "value", x] => multi_pv = x.parse().unwrap(),
+                ["setoption", "name", "Ponder", "value", _] => {}
+                ["setoption", "name", "Deterministic", "value", x] => deterministic = x == "true",
+                ["setoption", "name", "EvalFile", "value", ref path @ ..] => {
+                    set_eval_file(&path.join(" "));
+                    pool.clear_accumulators();
+
+                    // stored static evals came from the previous network
+                    idle(&mut tt).clear(threads);
+                }
                 #[cfg(feature = "tuning")]
                 ["setoption", "name", name, "value", x] => set_param(name, x.parse().unwrap()),
                 _ => {}
             },
-            "go" => handle_go(
-                commands,
-                &pos,
-                &castling,
-                stack.clone(),
-                &mut htable,
-                &mut chtable,
-                &mut stored_message,
-                &tt,
-                threads,
-            ),
+            "go" => {
+                // every search starts from the same state, so output depends only on input
+                if deterministic {
+                    idle(&mut tt).clear(threads);
+                    pool.clear();
+                }
+
+                handle_go(
+                    commands,
+                    &pos,
+                    &castling,
+                    stack.clone(),
+                    &mut pool,
+                    &mut stored_message,
+                    &tt,
+                    multi_pv,
+                    deterministic,
+                )
+            }
             "position" => set_position(commands, &mut pos, &mut stack, &mut castling),
             "perft" => run_perft(commands, &pos, &castling),
             "quit" => process::exit(0),
+            "fen" => println!("{}", pos.fen(&castling)),
+            "d" => print_position(&pos, &castling),
+            "trace" => print_trace(&pos, &pool.main_tables().chtable),
+            "hashstats" => print_hash_stats(&tt),
+            "savehash" => match tt.save(&commands[1..].join(" ")) {
+                Ok(()) => println!("info string saved hash table"),
+                Err(err) => println!("info string failed to save hash table: {err}"),
+            },
+            "loadhash" => match idle(&mut tt).load(&commands[1..].join(" ")) {
+                Ok(()) => println!("info string loaded hash table"),
+                Err(err) => println!("info string failed to load hash table: {err}"),
+            },
             "eval" => {
                 println!("eval: {}cp", pos.eval_from_scratch());
             }
@@ -92,7 +169,12 @@ pub fn run_uci() {
     }
 }
 
-fn handle_search_input(abort: &AtomicBool) -> Option<String> {
+// the hash table is only shared with the search threads during a search
+fn idle(tt: &mut Arc<HashTable>) -> &mut HashTable {
+    Arc::get_mut(tt).expect("hash table is not in use outside of a search")
+}
+
+fn handle_search_input(abort: &AtomicBool, infinite: &AtomicBool) -> Option<String> {
     loop {
         let mut input = String::new();
         let bytes_read = io::stdin().read_line(&mut input).unwrap();
@@ -106,14 +188,198 @@ fn handle_search_input(abort: &AtomicBool) -> Option<String> {
             "isready" => println!("readyok"),
             "quit" => process::exit(0),
             "stop" => {
-                abort.store(true, std::sync::atomic::Ordering::Relaxed);
+                abort.store(true, Relaxed);
                 return None;
             }
-            _ => return Some(input),
+            "ponderhit" => infinite.store(false, Relaxed),
+            // any other command stops the search before it is handled
+            _ => {
+                abort.store(true, Relaxed);
+                infinite.store(false, Relaxed);
+                return Some(input);
+            }
         };
     }
 }
 
+fn set_eval_file(path: &str) {
+    if path.is_empty() || path == EMBEDDED_NET {
+        Network::use_embedded();
+        println!("info string using embedded network");
+        return;
+    }
+
+    match Network::load(path) {
+        Ok(()) => println!("info string loaded network {path}"),
+        Err(err) => println!("info string failed to load network {path}: {err}"),
+    }
+}
+
+fn sq_name(sq: usize) -> String {
+    format!("{}{}", (b'a' + sq as u8 % 8) as char, sq / 8 + 1)
+}
+
+fn print_position(pos: &Position, castling: &Castling) {
+    let line = " +---+---+---+---+---+---+---+---+";
+
+    println!("{line}");
+    for row in (0..8).rev() {
+        print!(" |");
+        for col in 0..8 {
+            print!(" {} |", pos.piece_char(8 * row + col).unwrap_or(' '));
+        }
+        println!(" {}", row + 1);
+        println!("{line}");
+    }
+    println!("   a   b   c   d   e   f   g   h");
+    println!();
+
+    let mut checkers = pos.checkers();
+    let mut checker_sqs = Vec::new();
+    bitloop!(|checkers, sq| checker_sqs.push(sq_name(usize::from(sq))));
+
+    let mut rights = Vec::new();
+    let names = [
+        (Rights::WKS, 'K'),
+        (Rights::WQS, 'Q'),
+        (Rights::BKS, 'k'),
+        (Rights::BQS, 'q'),
+    ];
+    for (i, (right, name)) in names.into_iter().enumerate() {
+        if pos.rights() & right > 0 {
+            let (side, ks) = (i / 2, 1 - i % 2);
+            let rook = 56 * side + usize::from(castling.rook_file(side, ks));
+            rights.push(format!("{name} ({})", sq_name(rook)));
+        }
+    }
+
+    println!("Fen: {}", pos.fen(castling));
+    println!("Hash: {:016X}", pos.hash());
+    println!("Pawn Hash: {:016X}", pos.pawnhash());
+    println!("Checkers: {}", checker_sqs.join(" "));
+    println!("Castling: {}", rights.join(" "));
+    println!("Chess960: {}", castling.is_chess960());
+    println!("Kernels: {}", kernels().name);
+    println!("Eval: {}cp", pos.eval_from_scratch());
+}
+
+fn print_hash_stats(tt: &HashTable) {
+    let stats = tt.stats();
+    let percent = |count: usize, total: usize| 100.0 * count as f64 / total.max(1) as f64;
+
+    println!("Entries: {}", stats.entries);
+    println!(
+        "Used: {} ({:.2}%)",
+        stats.used,
+        percent(stats.used, stats.entries)
+    );
+    println!(
+        "Was PV: {} ({:.2}%)",
+        stats.was_pv,
+        percent(stats.was_pv, stats.used)
+    );
+
+    // percentages are of used entries, bars are relative to the largest row
+    let histogram = |title: &str, rows: Vec<(String, usize)>| {
+        let max = rows.iter().map(|row| row.1).max().unwrap_or(0);
+
+        println!();
+        println!("{title:<8} {:>10} {:>8}", "entries", "%");
+        for (label, count) in rows.into_iter().filter(|row| row.1 > 0) {
+            let bar = "#".repeat(40 * count / max);
+            let share = percent(count, stats.used);
+            let row = format!("{label:<8} {count:>10} {share:>7.2}% {bar}");
+            println!("{}", row.trim_end());
+        }
+    };
+
+    let depths = stats.depths.chunks(4).enumerate();
+    histogram(
+        "Depth",
+        depths
+            .map(|(i, counts)| (format!("{}-{}", 4 * i, 4 * i + 3), counts.iter().sum()))
+            .collect(),
+    );
+
+    let bounds = ["lower", "exact", "upper"].into_iter().zip(stats.bounds);
+    histogram(
+        "Bound",
+        bounds
+            .map(|(name, count)| (name.to_string(), count))
+            .collect(),
+    );
+
+    // age is the number of searches since the entry was written
+    let ages = stats.ages.into_iter().enumerate();
+    histogram(
+        "Age",
+        ages.map(|(age, count)| (age.to_string(), count)).collect(),
+    );
+}
+
+fn print_trace(pos: &Position, chtable: &CorrectionHistoryTable) {
+    // board map is from white's perspective so it reads the same either side to move
+    let white_eval = |pos: &Position| [1, -1][pos.stm()] * pos.eval_from_scratch();
+    let eval = white_eval(pos);
+    let line = format!(" +{}", "-------+".repeat(8));
+
+    println!("{line}");
+    for row in (0..8).rev() {
+        let mut pieces = String::from(" |");
+        let mut changes = String::from(" |");
+
+        for col in 0..8 {
+            let sq = 8 * row + col;
+            pieces += &format!("   {}   |", pos.piece_char(sq).unwrap_or(' '));
+
+            let pc = pos.get_pc(1 << sq);
+            if pc == Piece::EMPTY || pc == Piece::KING {
+                changes += "       |";
+            } else {
+                let mut removed = *pos;
+                removed.remove_piece(sq);
+                changes += &format!(" {:>+5} |", eval - white_eval(&removed));
+            }
+        }
+
+        println!("{pieces} {}", row + 1);
+        println!("{changes}");
+        println!("{line}");
+    }
+
+    let files = (b'a'..=b'h').map(|f| format!("    {}   ", f as char));
+    println!("{}", files.collect::<String>().trim_end());
+    println!();
+    println!("Change in eval when each piece is removed, from white's perspective");
+    println!();
+
+    let bucket = |bucket: usize| {
+        let mirrored = if bucket >= NUM_BUCKETS {
+            ", mirrored"
+        } else {
+            ""
+        };
+        format!("{}{mirrored}", bucket % NUM_BUCKETS)
+    };
+
+    let [white, black] = pos.king_buckets();
+    let raw = pos.raw_eval();
+    let scaled = pos.eval_from_scratch();
+    let corrected = chtable.correct_evaluation(pos, scaled);
+
+    println!("White king bucket: {}", bucket(white));
+    println!("Black king bucket: {}", bucket(black));
+    println!("Output bucket: {}", pos.output_bucket());
+    println!("Raw network output: {raw}cp");
+    println!(
+        "Material scale: {:.3}",
+        f64::from(pos.material_scale()) / 1024.0
+    );
+    println!("Scaled eval: {scaled}cp");
+    println!("Correction history: {:+}cp", corrected - scaled);
+    println!("Final eval: {corrected}cp (side to move)");
+}
+
 fn perft<const ROOT: bool>(pos: &Position, castling: &Castling, depth: u8) -> u64 {
     let moves = pos.movegen::<true>(castling);
     let mut positions = 0;
@@ -150,33 +416,44 @@ fn run_perft(commands: Vec<&str>, pos: &Position, castling: &Castling) {
     );
 }
 
-fn run_bench(
-    tt: &HashTable,
-    stack: Vec<u64>,
-    htable: &HistoryTable,
-    chtable: &CorrectionHistoryTable,
-) {
-    let abort = AtomicBool::new(false);
-    let mut td = ThreadData::new(
-        &abort,
-        tt,
-        stack,
-        htable.clone(),
-        chtable.clone(),
-        Castling::default(),
-    );
+fn run_bench(threads: usize) {
+    let mut tt = Arc::new(HashTable::default());
+    let mut pool = ThreadPool::default();
+    idle(&mut tt).resize(16, threads);
+    pool.resize(threads);
+
     let mut total_nodes = 0;
     let mut total_time = 0;
     let mut eval = 0i32;
-    td.max_time = 30000;
     let bench_fens = FEN_STRING.split('\n').collect::<Vec<&str>>();
     for fen in bench_fens {
-        let pos = Position::from_fen(fen, &mut td.castling);
+        let mut castling = Castling::default();
+        let pos = Position::from_fen(fen, &mut castling).unwrap();
         eval = eval.wrapping_add([1, -1][pos.stm()] * pos.eval_from_scratch());
+
+        let abort = Arc::new(AtomicBool::new(false));
+        let infinite = Arc::new(AtomicBool::new(false));
+        let nodes = Arc::new(AtomicU64::new(0));
         let timer = Instant::now();
-        go(&pos, &mut td, false, 11, 1_000_000.0, u64::MAX);
+
+        // every thread searches to the full depth
+        pool.start(|_| {
+            let (abort, infinite, tt, nodes) =
+                (abort.clone(), infinite.clone(), tt.clone(), nodes.clone());
+
+            Box::new(move |tables| {
+                let mut td =
+                    ThreadData::new(&abort, &infinite, &nodes, &tt, Vec::new(), tables, castling);
+                td.max_time = 30000;
+                go(&pos, &mut td, false, 11, 1_000_000.0, u64::MAX);
+                td.into_tables()
+            })
+        });
+
+        pool.wait();
+        pool.merge_histories();
         total_time += timer.elapsed().as_millis();
-        total_nodes += td.nodes();
+        total_nodes += nodes.load(Relaxed);
         tt.age_up();
     }
     println!("Summed Eval: {eval}");
@@ -192,6 +469,10 @@ fn preamble() {
     println!("option name Threads type spin default 1 min 1 max 512");
     println!("option name Hash type spin default 16 min 1 max 1048576");
     println!("option name Clear Hash type button");
+    println!("option name MultiPV type spin default 1 min 1 max 256");
+    println!("option name Ponder type check default false");
+    println!("option name Deterministic type check default false");
+    println!("option name EvalFile type string default {EMBEDDED_NET}");
     println!("option name UCI_Chess960 type check default false");
     #[cfg(feature = "tuning")]
     list_params();
@@ -222,18 +503,55 @@ fn set_position(
         }
     }
 
-    *pos = Position::from_fen(if fen.is_empty() { STARTPOS } else { &fen }, castling);
+    *pos = match Position::from_fen(if fen.is_empty() { STARTPOS } else { &fen }, castling) {
+        Ok(new) => new,
+        Err(err) => {
+            println!("info string invalid fen: {err}");
+            return;
+        }
+    };
     stack.clear();
 
     for m in move_list {
-        stack.push(pos.hash());
         let possible_moves = pos.movegen::<true>(castling);
+        let mut new = *pos;
 
-        for mov in possible_moves.iter() {
-            if m == mov.to_uci(castling) {
-                pos.make(*mov, castling);
-            }
+        let legal = match possible_moves.iter().find(|mov| m == mov.to_uci(castling)) {
+            Some(&mov) => !new.make(mov, castling),
+            None => false,
+        };
+
+        if !legal {
+            let kind = if is_uci_move(m) { "illegal" } else { "invalid" };
+            println!("info string {kind} move {m}, ignoring remaining moves");
+            return;
         }
+
+        stack.push(pos.hash());
+        *pos = new;
+    }
+}
+
+fn is_uci_move(m: &str) -> bool {
+    let chs = m.as_bytes();
+    let sq = |i: usize| (b'a'..=b'h').contains(&chs[i]) && (b'1'..=b'8').contains(&chs[i + 1]);
+    match chs.len() {
+        4 => sq(0) && sq(2),
+        5 => sq(0) && sq(2) && b"nbrq".contains(&chs[4]),
+        _ => false,
+    }
+}
+
+fn report_mate_search(n: i32, score: i32, completed_depth: i32) {
+    if score >= Score::MATE {
+        println!(
+            "info string proved mate in {}",
+            (Score::MAX - score + 1) / 2
+        );
+    } else if score <= -Score::MATE || completed_depth >= 2 * n - 1 {
+        println!("info string proved no mate in {n}");
+    } else {
+        println!("info string search stopped before mate in {n} was resolved");
     }
 }
 
@@ -243,11 +561,11 @@ fn handle_go(
     pos: &Position,
     castling: &Castling,
     stack: Vec<u64>,
-    htable: &mut HistoryTable,
-    chtable: &mut CorrectionHistoryTable,
+    pool: &mut ThreadPool,
     stored_message: &mut Option<String>,
-    tt: &HashTable,
-    threads: usize,
+    tt: &Arc<HashTable>,
+    multi_pv: usize,
+    deterministic: bool,
 ) {
     let mut token = 0;
     let mut times = [0, 0];
@@ -256,6 +574,10 @@ fn handle_go(
     let mut incs = [0, 0];
     let mut depth = 64;
     let mut nodes = u64::MAX;
+    let mut infinite = false;
+    let mut search_moves = Vec::new();
+    let mut mate = None;
+    let mut fixed_limit = false;
 
     let tokens = [
         "go",
@@ -267,10 +589,14 @@ fn handle_go(
         "binc",
         "depth",
         "nodes",
+        "searchmoves",
+        "mate",
     ];
 
     for cmd in commands {
-        if let Some(x) = tokens.iter().position(|&y| y == cmd) {
+        if ["infinite", "ponder"].contains(&cmd) {
+            infinite = true;
+        } else if let Some(x) = tokens.iter().position(|&y| y == cmd) {
             token = x
         } else if let Ok(val) = cmd.parse::<i64>() {
             match token {
@@ -281,13 +607,40 @@ fn handle_go(
                 2 | 3 => times[token - 2] = val.max(0),
                 4 => mtg = val,
                 5 | 6 => incs[token - 5] = val.max(0),
-                7 => depth = val.clamp(0, 64) as i32,
-                8 => nodes = val as u64,
+                7 => {
+                    depth = val.clamp(0, 64) as i32;
+                    fixed_limit = true;
+                }
+                8 => {
+                    nodes = val as u64;
+                    fixed_limit = true;
+                }
+                10 => {
+                    mate = Some(val.clamp(1, 32) as i32);
+                    fixed_limit = true;
+                }
                 _ => {}
             }
+        } else if token == 9 {
+            search_moves.push(cmd);
         }
     }
 
+    let legal = pos.legal_moves(castling);
+    let mut root_moves = MoveList::ZEROED;
+    for m in search_moves {
+        match legal.iter().find(|mov| mov.to_uci(castling) == m) {
+            Some(&mov) if !root_moves.contains(&mov) => root_moves.add(mov),
+            Some(_) => {}
+            None => println!("info string ignoring illegal searchmove {m}"),
+        }
+    }
+
+    // a mate in n is proven by a full-width search of 2n - 1 plies
+    if let Some(n) = mate {
+        depth = depth.min(2 * n - 1);
+    }
+
     let side = pos.stm();
     let mut time = times[side];
     let inc = incs[side];
@@ -297,48 +650,101 @@ fn handle_go(
         time = alloc
     }
 
-    let abort = AtomicBool::new(false);
-
-    let hard_bound = (alloc * 2).clamp(1, 1.max(time - 10)) as u128;
-    let soft_bound = if mtg == 1 { alloc } else { alloc * 6 / 10 };
+    let abort = Arc::new(AtomicBool::new(false));
+    let infinite = Arc::new(AtomicBool::new(infinite));
+    let shared_nodes = Arc::new(AtomicU64::new(0));
 
-    // main search thread
-    let mut td = ThreadData::new(
-        &abort,
-        tt,
-        stack.clone(),
-        htable.clone(),
-        chtable.clone(),
-        *castling,
-    );
-    td.max_time = hard_bound;
-    td.max_nodes = nodes;
+    let mut hard_bound = (alloc * 2).clamp(1, 1.max(time - 10)) as u128;
+    let mut soft_bound = (if mtg == 1 { alloc } else { alloc * 6 / 10 }) as f64;
 
-    std::thread::scope(|s| {
-        s.spawn(|| {
-            let (bm, _) = go(pos, &mut td, true, depth, soft_bound as f64, u64::MAX);
+    // only node and depth limits are reproducible, a clock still has to be respected
+    if deterministic && fixed_limit {
+        hard_bound = u128::MAX;
+        soft_bound = f64::INFINITY;
+    }
 
-            println!("bestmove {}", bm.to_uci(castling));
-        });
+    // helpers send their results to the main thread to vote on the best move
+    let (result_sender, result_receiver) = channel();
+    let mut result_receiver = Some(result_receiver);
+
+    pool.start(|id| {
+        let (abort, infinite, tt) = (abort.clone(), infinite.clone(), tt.clone());
+        let (pos, castling, stack) = (*pos, *castling, stack.clone());
+        let shared_nodes = shared_nodes.clone();
+        let sender = (id > 0).then(|| result_sender.clone());
+        let receiver = if id == 0 {
+            result_receiver.take()
+        } else {
+            None
+        };
 
-        for _ in 0..(threads - 1) {
-            let mut sub = ThreadData::new(
+        Box::new(move |tables| {
+            let mut td = ThreadData::new(
                 &abort,
-                tt,
-                stack.clone(),
-                htable.clone(),
-                chtable.clone(),
-                *castling,
+                &infinite,
+                &shared_nodes,
+                &tt,
+                stack,
+                tables,
+                castling,
             );
-            sub.max_time = hard_bound;
-            s.spawn(move || go(pos, &mut sub, false, depth, soft_bound as f64, u64::MAX));
-        }
+            td.max_time = hard_bound;
+            td.max_nodes = nodes;
+            td.deterministic = deterministic;
+            td.root_moves = root_moves;
+            td.mate_search = mate.is_some();
+
+            // helper threads
+            if let Some(sender) = sender {
+                let (bm, score) = go(&pos, &mut td, false, depth, soft_bound, u64::MAX);
+                let _ = sender.send(td.result(bm, score));
+                return td.into_tables();
+            }
+
+            td.multi_pv = multi_pv;
 
-        *stored_message = handle_search_input(&abort);
+            let (bm, score) = go(&pos, &mut td, true, depth, soft_bound, u64::MAX);
+
+            if let Some(n) = mate {
+                report_mate_search(n, score, td.completed_depth);
+            }
+
+            // bestmove can't be sent in infinite mode until told to stop
+            while infinite.load(Relaxed) && !abort.load(Relaxed) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            // helpers still searching are stopped and report what they have
+            abort.store(true, Relaxed);
+            let mut results = vec![td.result(bm, score)];
+            results.extend(receiver.into_iter().flatten());
+
+            // lines from helpers can't be matched up with the other multipv lines
+            let best = if multi_pv > 1 { 0 } else { vote(&results) };
+            let res = results[best];
+
+            if best > 0 {
+                print_info(&td, res.depth, res.seldepth, res.score, &res.pv, None);
+            }
+
+            print!("bestmove {}", res.best_move.to_uci(&castling));
+
+            if let Some(ponder) = res.pv.get(1) {
+                print!(" ponder {}", ponder.to_uci(&castling));
+            }
+
+            println!();
+            td.into_tables()
+        })
     });
 
-    *htable = td.htable;
-    *chtable = td.chtable;
-    chtable.age_entries();
+    // the main thread's receiver finishes once every helper has sent its result
+    drop(result_sender);
+
+    *stored_message = handle_search_input(&abort, &infinite);
+
+    pool.wait();
+    pool.merge_histories();
+    pool.age_entries();
     tt.age_up();
 }